@@ -0,0 +1,145 @@
+use crate::git;
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// How often the repository directory is scanned for idle repositories
+pub const ARCHIVE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// What happens to a repository that has been idle too long
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ArchiveAction {
+    /// Keep it in place but refuse pushes and branch changes
+    ReadOnly,
+    /// Move it under this directory, keeping its path relative to the
+    /// repository directory
+    Move(PathBuf),
+}
+
+/// Periodic job archiving repositories whose refs haven't changed (see
+/// [`git::last_ref_update`]) for longer than a threshold
+#[derive(Debug, Clone)]
+pub struct Archiver {
+    idle_after: Duration,
+    action: ArchiveAction,
+}
+
+impl Archiver {
+    pub fn new(idle_after: Duration, action: ArchiveAction) -> Self {
+        Self { idle_after, action }
+    }
+
+    /// Archive every repository under `repos_dir` idle since before
+    /// `now - idle_after`, returning how many were archived. Failures are
+    /// logged and don't stop the scan.
+    pub fn run_once(&self, repos_dir: &Path, now: DateTime<Utc>) -> usize {
+        let Ok(idle_after) = chrono::Duration::from_std(self.idle_after) else {
+            return 0;
+        };
+        let cutoff = now - idle_after;
+
+        let mut archived = 0;
+        for name in git::discover_repos(repos_dir) {
+            let repo_path = repos_dir.join(&name);
+            if self.action == ArchiveAction::ReadOnly && git::is_archived(&repo_path) {
+                continue;
+            }
+            let Some(last_activity) = git::last_ref_update(&repo_path) else {
+                continue;
+            };
+            if last_activity >= cutoff {
+                continue;
+            }
+
+            match self.archive(&repo_path, &name) {
+                Ok(()) => {
+                    archived += 1;
+                    tracing::info!(
+                        "Archived {} (last activity {}): {}",
+                        name,
+                        last_activity.format("%Y-%m-%d"),
+                        self.describe(&name)
+                    );
+                }
+                Err(e) => tracing::warn!("Failed to archive {}: {:#}", name, e),
+            }
+        }
+        archived
+    }
+
+    fn archive(&self, repo_path: &Path, name: &str) -> Result<()> {
+        match &self.action {
+            ArchiveAction::ReadOnly => Ok(git::set_archived(repo_path)?),
+            ArchiveAction::Move(archive_dir) => {
+                let target = archive_dir.join(name);
+                if target.exists() {
+                    anyhow::bail!("{:?} already exists", target);
+                }
+                if let Some(parent) = target.parent() {
+                    fs::create_dir_all(parent)
+                        .with_context(|| format!("Failed to create {:?}", parent))?;
+                }
+                fs::rename(repo_path, &target)
+                    .with_context(|| format!("Failed to move to {:?}", target))
+            }
+        }
+    }
+
+    fn describe(&self, name: &str) -> String {
+        match &self.action {
+            ArchiveAction::ReadOnly => "made read-only".to_string(),
+            ArchiveAction::Move(archive_dir) => format!("moved to {:?}", archive_dir.join(name)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn archives_only_idle_repos() {
+        let root = std::env::temp_dir().join(format!("agito-archive-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        let repos = root.join("repos");
+
+        let commit = |name: &str, date: &str| {
+            let path = repos.join(name);
+            // Commits made long ago are still fresh when pushed now
+            let date_env = "2000-01-01T00:00:00Z";
+            let env = [("GIT_AUTHOR_DATE", date_env), ("GIT_COMMITTER_DATE", date_env)];
+            git::test_util::test_repo_with_commit(&path, &env);
+
+            let date: DateTime<Utc> = date.parse().unwrap();
+            for ref_path in ["refs", "refs/heads", "refs/heads/main", "refs/tags"] {
+                let file = fs::File::open(path.join(ref_path)).unwrap();
+                file.set_modified(date.into()).unwrap();
+            }
+        };
+        commit("old.git", "2020-01-01T00:00:00Z");
+        commit("alice/old.git", "2020-06-01T00:00:00Z");
+        commit("new.git", "2024-05-01T00:00:00Z");
+
+        let now = "2024-06-01T00:00:00Z".parse().unwrap();
+        let year = Duration::from_secs(365 * 24 * 60 * 60);
+
+        let read_only = Archiver::new(year, ArchiveAction::ReadOnly);
+        assert_eq!(read_only.run_once(&repos, now), 2);
+        assert!(git::is_archived(&repos.join("old.git")));
+        assert!(git::is_archived(&repos.join("alice/old.git")));
+        assert!(!git::is_archived(&repos.join("new.git")));
+        // Already read-only repositories are left alone
+        assert_eq!(read_only.run_once(&repos, now), 0);
+
+        let archive_dir = root.join("archive");
+        let mover = Archiver::new(year, ArchiveAction::Move(archive_dir.clone()));
+        assert_eq!(mover.run_once(&repos, now), 2);
+        assert!(archive_dir.join("alice/old.git/HEAD").is_file());
+        assert!(!repos.join("old.git").exists());
+        assert_eq!(git::discover_repos(&repos), ["new.git"]);
+
+        let _ = fs::remove_dir_all(&root);
+    }
+}
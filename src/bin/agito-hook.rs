@@ -0,0 +1,106 @@
+use agito::{git, hooks};
+use std::env;
+use std::io::{self, BufRead};
+use std::path::PathBuf;
+use std::process::exit;
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+
+    if args.len() < 2 {
+        print_usage();
+        exit(1);
+    }
+
+    if let Some(binary) = env::var_os(git::GIT_BINARY_ENV) {
+        git::set_git_binary(binary);
+    }
+
+    match args[1].as_str() {
+        "pre-receive" => handle_pre_receive(),
+        "update" => handle_update(&args[2..]),
+        "post-receive" => handle_post_receive(),
+        "help" | "--help" | "-h" => print_usage(),
+        other => {
+            eprintln!("Unknown hook: {}", other);
+            exit(1);
+        }
+    }
+}
+
+fn print_usage() {
+    let usage = r#"agito-hook - Server-side git hooks for agito repositories
+
+Usage:
+  agito-hook pre-receive < <oldrev> <newrev> <refname> lines
+  agito-hook update <refname> <oldrev> <newrev>
+  agito-hook post-receive < <oldrev> <newrev> <refname> lines
+      (also runs the repository's agito-ci.sh for each ref, streaming its output)
+
+Installed into repository hooks by agito-server; not meant to be run by hand.
+"#;
+    println!("{}", usage);
+}
+
+fn handle_pre_receive() {
+    let updates: Vec<(String, String, String)> = io::stdin()
+        .lock()
+        .lines()
+        .map_while(Result::ok)
+        .filter_map(|line| {
+            let parts: Vec<&str> = line.split_whitespace().collect();
+            let [oldrev, newrev, refname] = parts[..] else {
+                return None;
+            };
+            Some((oldrev.to_string(), newrev.to_string(), refname.to_string()))
+        })
+        .collect();
+
+    if let Err(e) = hooks::check_pre_receive(&repo_path(), &updates) {
+        eprintln!("Error: {}", e);
+        exit(1);
+    }
+}
+
+fn handle_update(args: &[String]) {
+    if args.len() != 3 {
+        eprintln!("Error: update requires <refname> <oldrev> <newrev>");
+        exit(1);
+    }
+
+    if let Err(e) = hooks::check_update(&repo_path(), &args[0], &args[1], &args[2]) {
+        eprintln!("Error: {}", e);
+        exit(1);
+    }
+}
+
+fn handle_post_receive() {
+    let repo_path = repo_path();
+
+    for line in io::stdin().lock().lines() {
+        let Ok(line) = line else {
+            break;
+        };
+        let parts: Vec<&str> = line.split_whitespace().collect();
+        let [oldrev, newrev, refname] = parts[..] else {
+            continue;
+        };
+
+        // The push has already landed, so only warn
+        if let Err(e) = hooks::post_receive(&repo_path, refname, newrev) {
+            eprintln!("Warning: {}", e);
+        }
+
+        // stderr is unbuffered, so CI output reaches the client as it is made
+        if let Err(e) = hooks::run_ci(&repo_path, refname, oldrev, newrev, &mut io::stderr()) {
+            eprintln!("Warning: {}", e);
+        }
+    }
+}
+
+/// Git runs hooks from inside the repository with GIT_DIR set
+fn repo_path() -> PathBuf {
+    env::var_os("GIT_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from("."))
+}
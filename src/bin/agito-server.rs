@@ -1,17 +1,33 @@
-use agito::{ssh, web};
-use anyhow::Result;
+use agito::archive::{ArchiveAction, Archiver};
+use agito::lfs::{self, LfsStore};
+use agito::maintenance::{self, Maintenance};
+use agito::{git, git_io, net, ssh, web, web_auth, Server};
+use anyhow::{Context, Result};
+use axum::http::HeaderValue;
 use clap::Parser;
-use std::path::PathBuf;
+use regex::Regex;
+use std::net::IpAddr;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 use tokio::signal;
+use tracing_subscriber::filter::{LevelFilter, Targets};
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::{fmt, EnvFilter};
 
 #[derive(Parser, Debug)]
 #[command(name = "agito-server")]
 #[command(about = "Agito Git Server", long_about = None)]
+#[command(version = agito::VERSION)]
 struct Args {
     /// Directory to store repositories
-    #[arg(long, default_value = "/var/lib/agito/repos")]
+    #[arg(long, default_value = "/var/lib/agito/repos", value_parser = resolve_path)]
     repos: PathBuf,
 
+    /// Address to listen on; the default `::` accepts both IPv6 and IPv4
+    #[arg(long, default_value_t = net::DEFAULT_BIND_ADDR)]
+    bind: IpAddr,
+
     /// HTTP port for web viewer
     #[arg(long, default_value = "3000")]
     http_port: String,
@@ -21,72 +37,736 @@ struct Args {
     ssh_port: String,
 
     /// SSH host key file
-    #[arg(long, default_value = "/var/lib/agito/ssh/host_key")]
+    #[arg(long, default_value = "/var/lib/agito/ssh/host_key", value_parser = resolve_path)]
     ssh_key: PathBuf,
 
-    /// Authorized keys file
-    #[arg(long, default_value = "/var/lib/agito/ssh/authorized_keys")]
+    /// Authorized keys file, or a directory whose files are all read
+    #[arg(long, default_value = "/var/lib/agito/ssh/authorized_keys", value_parser = resolve_path)]
     authorized_keys: PathBuf,
+
+    /// Seconds of client silence before sending an SSH keepalive (0 disables)
+    #[arg(long, default_value = "30")]
+    ssh_keepalive_interval: u64,
+
+    /// Unanswered SSH keepalives before the connection is dropped
+    #[arg(long, default_value = "3")]
+    ssh_keepalive_max: usize,
+
+    /// Bytes read from git at a time when streaming to SSH clients
+    #[arg(long, default_value_t = git_io::DEFAULT_BUFFER_SIZE)]
+    io_buffer_size: usize,
+
+    /// Maximum concurrent session channels per SSH connection
+    #[arg(long, default_value = "4")]
+    ssh_max_channels: usize,
+
+    /// PEM certificate chain for serving the web viewer over HTTPS
+    #[arg(long, requires = "tls_key", value_parser = resolve_path)]
+    tls_cert: Option<PathBuf>,
+
+    /// PEM private key matching --tls-cert
+    #[arg(long, requires = "tls_cert", value_parser = resolve_path)]
+    tls_key: Option<PathBuf>,
+
+    /// Serve SSH and HTTP together on this single port instead of
+    /// --http-port and --ssh-port
+    #[arg(long, conflicts_with = "tls_cert")]
+    combined_port: Option<String>,
+
+    /// Don't run the web viewer, e.g. for a push-only server
+    #[arg(long, conflicts_with_all = ["no_ssh", "combined_port"])]
+    no_web: bool,
+
+    /// Don't run the SSH server, e.g. for a read-only web mirror. No host
+    /// key is needed
+    #[arg(long, conflicts_with = "combined_port")]
+    no_ssh: bool,
+
+    /// Show agito status messages on the client's stderr during clone/fetch/push
+    #[arg(long)]
+    ssh_progress: bool,
+
+    /// Refuse to serve clones and fetches of repositories whose HEAD does
+    /// not resolve, instead of streaming a broken pack
+    #[arg(long)]
+    check_repo_health: bool,
+
+    /// Allow partial clones such as `git clone --filter=blob:none`, for
+    /// large repositories whose users don't need every blob up front
+    #[arg(long)]
+    partial_clone: bool,
+
+    /// Enable `agito-import <name> <url>`, which makes the server fetch a
+    /// repository from a public https:// URL
+    #[arg(long)]
+    allow_import: bool,
+
+    /// Regular expression that names of new repositories, without `.git`,
+    /// must match, e.g. `^[a-z][a-z0-9-]{2,63}$`
+    #[arg(long, value_parser = parse_repo_name_pattern)]
+    repo_name_pattern: Option<Regex>,
+
+    /// Most refs (branches, tags and others) a repository may have before
+    /// pushes that add more are rejected; a repository's `agito.maxRefs`
+    /// config overrides it. 0 means unlimited
+    #[arg(long, default_value = "0")]
+    max_refs: usize,
+
+    /// Create each user's repositories under `<repos>/<user>/`, the user
+    /// being the key's `agito-user` option, and resolve bare repository
+    /// names there first
+    #[arg(long)]
+    user_namespaces: bool,
+
+    /// `agito-user` exempt from --user-namespaces when creating repositories
+    /// and allowed to change any user's (repeatable)
+    #[arg(long = "admin-user", requires = "user_namespaces")]
+    admin_users: Vec<String>,
+
+    /// Only let keys with the `agito-admin` option in authorized_keys create
+    /// or import repositories
+    #[arg(long)]
+    no_self_serve: bool,
+
+    /// File whose contents are shown on the client's stderr before every
+    /// SSH command; re-read when it changes
+    #[arg(long, value_parser = resolve_path)]
+    motd_file: Option<PathBuf>,
+
+    /// File whose contents are sent to SSH clients before they
+    /// authenticate, e.g. a legal notice; read once at startup
+    #[arg(long, value_parser = resolve_path)]
+    ssh_banner_file: Option<PathBuf>,
+
+    /// Custom stylesheet linked from every web page after the built-in styles
+    #[arg(long, value_parser = resolve_path)]
+    web_css: Option<PathBuf>,
+
+    /// Directory of extra files to serve under /static, e.g. images
+    /// referenced from --web-css
+    #[arg(long, value_parser = resolve_path)]
+    static_dir: Option<PathBuf>,
+
+    /// Largest file in bytes shown inline in the web viewer; bigger files
+    /// link to a download instead
+    #[arg(long, default_value_t = web::DEFAULT_MAX_BLOB_SIZE)]
+    max_blob_size: u64,
+
+    /// Origin allowed to call the JSON API from a browser, or `*` for any
+    #[arg(long, value_parser = parse_cors_origin)]
+    cors_origin: Option<HeaderValue>,
+
+    /// Seconds a temporary pack file must be untouched before it is treated
+    /// as left over from an interrupted push and removed at startup or by
+    /// `agito-clean` (0 skips the startup scan and keeps the default for
+    /// `agito-clean`)
+    #[arg(long, default_value_t = ssh::DEFAULT_STALE_PACK_AGE.as_secs())]
+    stale_pack_age: u64,
+
+    /// Archive repositories with no pushes or commits for this many days,
+    /// checked hourly (0 disables archiving)
+    #[arg(long, default_value = "0")]
+    archive_after: u64,
+
+    /// What to do with idle repositories: `read-only` refuses pushes to them,
+    /// `move` moves them to --archive-dir
+    #[arg(long, default_value = "read-only", value_parser = ["read-only", "move"])]
+    archive_action: String,
+
+    /// Directory idle repositories are moved to with `--archive-action move`
+    #[arg(long, required_if_eq("archive_action", "move"), value_parser = resolve_path)]
+    archive_dir: Option<PathBuf>,
+
+    /// Issue tracker URL for `#123` references in commit messages, with
+    /// `{id}` standing for the number
+    #[arg(long)]
+    issue_url: Option<String>,
+
+    /// Refuse pushes and show a banner on web pages; clones and fetches
+    /// keep working
+    #[arg(long)]
+    maintenance: bool,
+
+    /// Enter maintenance mode while this file exists; re-checked on SIGHUP
+    #[arg(long, value_parser = resolve_path)]
+    maintenance_file: Option<PathBuf>,
+
+    /// Text shown in the web banner and to SSH commands refused during
+    /// maintenance
+    #[arg(long, default_value = maintenance::DEFAULT_MESSAGE)]
+    maintenance_message: String,
+
+    /// File of `user:<argon2 hash>` lines allowed to edit repositories
+    /// from the web viewer with HTTP Basic auth; editing is off without it
+    #[arg(long, value_parser = resolve_path)]
+    web_auth_file: Option<PathBuf>,
+
+    /// Append a line per web request (method, path, repository, status and
+    /// duration) to this file, separately from the application log
+    #[arg(long, value_parser = resolve_path)]
+    web_access_log: Option<PathBuf>,
+
+    /// File of `user: pattern...` lines limiting which repositories each web
+    /// user sees; `*: pattern...` applies to everyone, signed in or not
+    #[arg(long, requires = "web_auth_file", value_parser = resolve_path)]
+    web_acl_file: Option<PathBuf>,
+
+    /// Serve the Git LFS batch API at /repo/<name>/info/lfs. Uploads need
+    /// --web-auth-file credentials
+    #[arg(long, requires = "public_url")]
+    lfs: bool,
+
+    /// Keep LFS objects under this directory instead of inside each
+    /// repository
+    #[arg(long, requires = "lfs", value_parser = resolve_path)]
+    lfs_dir: Option<PathBuf>,
+
+    /// Largest LFS object in bytes that may be uploaded
+    #[arg(long, requires = "lfs", default_value_t = lfs::DEFAULT_MAX_OBJECT_SIZE)]
+    lfs_max_object_size: u64,
+
+    /// URL clients reach the web viewer at, e.g. `https://git.example.com`,
+    /// used for absolute links in the sitemap and LFS responses rather than
+    /// the request's Host header
+    #[arg(long, value_parser = parse_origin)]
+    public_url: Option<String>,
+
+    /// Serve totals of the bytes sent and received by SSH git transfers at
+    /// /metrics, in the Prometheus text format
+    #[arg(long)]
+    metrics: bool,
+
+    /// Ask all crawlers to stay away in /robots.txt, for private instances.
+    /// Without it only the pages that are expensive to render are excluded
+    #[arg(long)]
+    no_index: bool,
+
+    /// Seconds to cache the repository index page (0 disables caching)
+    #[arg(long, default_value = "30")]
+    index_cache_ttl: u64,
+
+    /// Check the signatures of commits shown in the web viewer, marking them
+    /// Verified, Bad signature and so on
+    #[arg(long)]
+    verify_signatures: bool,
+
+    /// Allowed signers file for SSH commit signatures; overrides
+    /// `gpg.ssh.allowedSignersFile` in git config
+    #[arg(long, requires = "verify_signatures", value_parser = resolve_path)]
+    allowed_signers: Option<PathBuf>,
+
+    /// GnuPG home directory whose keyring checks GPG commit signatures
+    #[arg(long, requires = "verify_signatures", value_parser = resolve_path)]
+    gpg_home: Option<PathBuf>,
+
+    /// Git executable used for all repository operations
+    #[arg(long, env = "AGITO_GIT", default_value = "git")]
+    git_binary: PathBuf,
+
+    /// `agito-hook` executable that new repositories' hooks call; defaults
+    /// to the one next to agito-server
+    #[arg(long)]
+    hook_binary: Option<PathBuf>,
+
+    /// Similarity in percent at which commit pages and patches show a file
+    /// as renamed or copied rather than deleted and added
+    #[arg(long, default_value = "50", value_parser = clap::value_parser!(u8).range(0..=100))]
+    rename_threshold: u8,
+
+    /// Octal permissions for files in newly created repositories, e.g. 0660
+    /// to share them with the group; directories get matching execute bits.
+    /// Sets `core.sharedRepository` so git keeps new objects the same way
+    #[arg(long, value_parser = parse_repo_perms)]
+    repo_perms: Option<u32>,
+
+    /// Point new repositories' `core.hooksPath` at this directory instead of
+    /// giving each its own hooks. Created with the default hooks if missing
+    #[arg(long, value_parser = resolve_path)]
+    shared_hooks_dir: Option<PathBuf>,
+
+    /// Write the server's PID to this file, removing it on shutdown
+    #[arg(long, value_parser = resolve_path)]
+    pid_file: Option<PathBuf>,
+
+    /// Rewrite the hooks of every repository, and of --shared-hooks-dir,
+    /// with this version's and exit. Edits to those hooks are lost
+    #[arg(long)]
+    update_hooks: bool,
+
+    /// Read a password from stdin, print its hash for --web-auth-file and
+    /// exit
+    #[arg(long)]
+    hash_password: bool,
+
+    /// Check the repository directory, host key, authorized keys, git and
+    /// TLS files, print a report and exit without binding any ports
+    #[arg(long)]
+    check_config: bool,
+
+    /// Log more detail: -v for debug, -vv for trace. RUST_LOG overrides this
+    #[arg(short, long, action = clap::ArgAction::Count, conflicts_with = "quiet")]
+    verbose: u8,
+
+    /// Only log warnings and errors. RUST_LOG overrides this
+    #[arg(short, long)]
+    quiet: bool,
+}
+
+/// Expand a leading `~` and make the path absolute against the current
+/// directory, so paths mean the same thing under a shell and under systemd
+fn resolve_path(path: &str) -> Result<PathBuf, String> {
+    let path = match path.strip_prefix('~') {
+        Some(rest) if rest.is_empty() || rest.starts_with('/') => {
+            let home = std::env::var_os("HOME").ok_or("cannot expand '~': HOME is not set")?;
+            PathBuf::from(home).join(rest.trim_start_matches('/'))
+        }
+        _ => PathBuf::from(path),
+    };
+
+    if path.is_absolute() {
+        return Ok(path);
+    }
+
+    let cwd = std::env::current_dir().map_err(|e| format!("cannot resolve {:?}: {}", path, e))?;
+    Ok(cwd.join(path))
+}
+
+fn parse_repo_name_pattern(pattern: &str) -> Result<Regex, String> {
+    Regex::new(pattern).map_err(|e| e.to_string())
+}
+
+/// Parse a `--cors-origin`: `*`, or an origin as browsers send it in the
+/// `Origin` header, which is compared exactly
+fn parse_cors_origin(origin: &str) -> Result<HeaderValue, String> {
+    let origin = if origin == "*" { origin.to_string() } else { parse_origin(origin)? };
+    HeaderValue::from_str(&origin).map_err(|e| e.to_string())
+}
+
+/// Parse an http or https origin, `scheme://host[:port]`, dropping a
+/// trailing `/`. A `--public-url` can't have a path either, since every page
+/// is served from the root.
+fn parse_origin(url: &str) -> Result<String, String> {
+    let uri: axum::http::Uri = url.parse().map_err(|e| format!("{}", e))?;
+    let scheme = uri.scheme_str().unwrap_or_default();
+    if scheme != "http" && scheme != "https" {
+        return Err("must start with http:// or https://".to_string());
+    }
+    let Some(authority) = uri.authority() else {
+        return Err("must name a host".to_string());
+    };
+    let origin_only = matches!(uri.path(), "" | "/") && uri.query().is_none();
+    if authority.as_str().contains('@') || !origin_only {
+        return Err("must be just a scheme and host, like https://git.example.com".to_string());
+    }
+    Ok(format!("{}://{}", scheme, authority.as_str().to_ascii_lowercase()))
+}
+
+/// Parse an octal `--repo-perms` mode, which like git's must leave the owner
+/// able to read and write
+fn parse_repo_perms(mode: &str) -> Result<u32, String> {
+    let mode = u32::from_str_radix(mode, 8).map_err(|_| format!("{:?} is not an octal mode", mode))?;
+    if mode > 0o777 {
+        return Err(format!("{:o} is not a file mode; use three octal digits like 660", mode));
+    }
+    if mode & 0o600 != 0o600 {
+        return Err("the owner must be able to read and write".to_string());
+    }
+    Ok(mode)
+}
+
+/// Our PID recorded for process supervisors, removed when dropped so any
+/// early return after writing it cleans up too
+struct PidFile(PathBuf);
+
+impl PidFile {
+    /// Write the file, replacing one left behind by a server that didn't
+    /// shut down cleanly
+    fn create(path: &Path) -> Result<Self> {
+        if let Ok(old) = fs::read_to_string(path) {
+            tracing::warn!("Overwriting stale PID file {:?} (PID {})", path, old.trim());
+        }
+        fs::write(path, format!("{}\n", std::process::id()))
+            .with_context(|| format!("Failed to write PID file {:?}", path))?;
+        Ok(Self(path.to_path_buf()))
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.0) {
+            tracing::warn!("Failed to remove PID file {:?}: {}", self.0, e);
+        }
+    }
+}
+
+/// Log filter chosen by -v/--quiet, used when RUST_LOG is not set
+fn log_level(args: &Args) -> LevelFilter {
+    match (args.quiet, args.verbose) {
+        (true, _) => LevelFilter::WARN,
+        (false, 0) => LevelFilter::INFO,
+        (false, 1) => LevelFilter::DEBUG,
+        (false, _) => LevelFilter::TRACE,
+    }
+}
+
+/// Run the `--check-config` checks, printing one line per check. Returns
+/// whether all of them passed.
+async fn check_config(args: &Args) -> bool {
+    let mut checks = vec![("repositories", check_writable(&args.repos))];
+    if !args.no_ssh {
+        checks.push((
+            "host key",
+            ssh::check_host_key(&args.ssh_key).map(|fingerprint| match fingerprint {
+                Some(fingerprint) => format!("{:?} ({})", args.ssh_key, fingerprint),
+                None => format!("{:?} will be generated on startup", args.ssh_key),
+            }),
+        ));
+        checks.push((
+            "authorized keys",
+            ssh::check_authorized_keys(&args.authorized_keys)
+                .with_context(|| format!("Failed to read {:?}", args.authorized_keys))
+                .map(|(accepted, skipped)| match skipped {
+                    0 => format!("{} key(s)", accepted),
+                    _ => format!("{} key(s), {} malformed line(s) skipped", accepted, skipped),
+                }),
+        ));
+    }
+    checks.push(("git", check_git()));
+    if let Some(path) = args.ssh_banner_file.as_ref().filter(|_| !args.no_ssh) {
+        let banner = ssh::read_banner(path)
+            .map(|banner| format!("{:?} ({} line(s))", path, banner.lines().count()));
+        checks.push(("ssh banner", banner));
+    }
+    if let (Some(cert), Some(key), false) = (&args.tls_cert, &args.tls_key, args.no_web) {
+        let tls = web::check_tls(cert.clone(), key.clone()).await;
+        checks.push(("tls", tls.map(|()| format!("{:?}", cert))));
+    }
+
+    let mut ok = true;
+    for (name, result) in checks {
+        match result {
+            Ok(detail) => println!("ok    {}: {}", name, detail),
+            Err(e) => {
+                println!("FAIL  {}: {:#}", name, e);
+                ok = false;
+            }
+        }
+    }
+    ok
+}
+
+/// Bring the hooks of existing repositories up to date, for `--update-hooks`
+fn update_all_hooks(repos: &Path, shared_hooks_dir: Option<&Path>) -> Result<()> {
+    if let Some(dir) = shared_hooks_dir {
+        git::update_shared_hooks(dir).context("Failed to update shared hooks")?;
+        println!("Updated shared hooks in {}", dir.display());
+    }
+
+    let mut updated = 0;
+    let mut failed = 0;
+    for name in git::discover_repos(repos) {
+        match git::update_hooks(&repos.join(&name)) {
+            Ok(true) => updated += 1,
+            Ok(false) => {}
+            Err(e) => {
+                eprintln!("{}: {}", name, e);
+                failed += 1;
+            }
+        }
+    }
+    println!("Updated the hooks of {} repositories", updated);
+    if failed > 0 {
+        anyhow::bail!("Failed to update the hooks of {} repositories", failed);
+    }
+    Ok(())
+}
+
+/// Whether `path` is `dir` or inside it, following symlinks as far as the
+/// path exists
+fn is_within(path: &Path, dir: &Path) -> bool {
+    let resolve = |path: &Path| {
+        let Some(existing) = path.ancestors().find(|ancestor| ancestor.exists()) else {
+            return path.to_path_buf();
+        };
+        match (fs::canonicalize(existing), path.strip_prefix(existing)) {
+            (Ok(real), Ok(rest)) => real.join(rest),
+            _ => path.to_path_buf(),
+        }
+    };
+    resolve(path).starts_with(resolve(dir))
+}
+
+/// Whether agito can create files in `dir`, or create `dir` itself when it
+/// doesn't exist yet
+fn check_writable(dir: &Path) -> Result<String> {
+    let Some(existing) = dir.ancestors().find(|path| path.exists()) else {
+        anyhow::bail!("no existing parent directory for {:?}", dir);
+    };
+    if !existing.is_dir() {
+        anyhow::bail!("{:?} is not a directory", existing);
+    }
+
+    let probe = existing.join(format!(".agito-check-{}", std::process::id()));
+    fs::write(&probe, b"").with_context(|| format!("{:?} is not writable", existing))?;
+    let _ = fs::remove_file(&probe);
+
+    Ok(if existing == dir {
+        format!("{:?} is writable", dir)
+    } else {
+        format!("{:?} will be created", dir)
+    })
+}
+
+/// Version of the configured git executable, which must run
+fn check_git() -> Result<String> {
+    let output = git::git_command()
+        .arg("--version")
+        .output()
+        .context("Failed to run git")?;
+    if !output.status.success() {
+        anyhow::bail!("git --version exited with {}", output.status);
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    // Initialize tracing
-    tracing_subscriber::fmt::init();
-
     let args = Args::parse();
+    if args.hash_password {
+        let mut password = String::new();
+        std::io::stdin()
+            .read_line(&mut password)
+            .context("Failed to read password")?;
+        println!("{}", web_auth::hash_password(password.trim_end_matches(['\r', '\n'])));
+        return Ok(());
+    }
 
-    // Create directories if they don't exist
-    std::fs::create_dir_all(&args.repos)?;
-    
-    if let Some(parent) = args.ssh_key.parent() {
-        std::fs::create_dir_all(parent)?;
+    // Initialize tracing, keeping web requests out of the application log
+    let filter = EnvFilter::builder()
+        .with_default_directive(log_level(&args).into())
+        .from_env_lossy()
+        .add_directive(format!("{}=off", web::ACCESS_LOG_TARGET).parse()?);
+    let access_log = match &args.web_access_log {
+        Some(path) => {
+            let file = fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .with_context(|| format!("Failed to open access log {:?}", path))?;
+            let layer = fmt::layer()
+                .with_writer(std::sync::Mutex::new(file))
+                .with_ansi(false)
+                .with_level(false)
+                .with_target(false)
+                .with_filter(Targets::new().with_target(web::ACCESS_LOG_TARGET, LevelFilter::INFO));
+            Some(layer)
+        }
+        None => None,
+    };
+    tracing_subscriber::registry()
+        .with(fmt::layer().with_filter(filter))
+        .with(access_log)
+        .init();
+    git::set_git_binary(&args.git_binary);
+    if let Some(path) = &args.hook_binary {
+        let path = std::path::absolute(path).context("Failed to resolve --hook-binary")?;
+        git::set_hook_binary(path);
+    }
+    git::set_rename_threshold(args.rename_threshold);
+    if args.verify_signatures {
+        git::check_signatures(git::SignatureKeys {
+            allowed_signers: args.allowed_signers.clone(),
+            gnupg_home: args.gpg_home.clone(),
+        });
+    }
+    if let Some(mode) = args.repo_perms {
+        git::set_repo_permissions(mode);
+    }
+    if args.check_config {
+        // The report already says what is wrong
+        let ok = check_config(&args).await;
+        std::process::exit(if ok { 0 } else { 1 });
+    }
+
+    if args.update_hooks {
+        return update_all_hooks(&args.repos, args.shared_hooks_dir.as_deref());
+    }
+
+    // The next scan would find repositories moved there again
+    if let Some(dir) = &args.archive_dir {
+        if is_within(dir, &args.repos) {
+            anyhow::bail!("--archive-dir {:?} must be outside --repos", dir);
+        }
+    }
+
+    if let Some(dir) = args.shared_hooks_dir {
+        git::set_shared_hooks_dir(dir).context("Failed to set up shared hooks")?;
     }
 
     tracing::info!("Agito Server Starting...");
     tracing::info!("Repositories: {:?}", args.repos);
-    tracing::info!("HTTP Port: {}", args.http_port);
-    tracing::info!("SSH Port: {}", args.ssh_port);
-
-    // Start SSH server in a task
-    let ssh_server = ssh::Server::new(
-        args.ssh_port.clone(),
-        args.ssh_key,
-        args.authorized_keys,
-        args.repos.clone(),
-    );
-    
-    let ssh_handle = tokio::spawn(async move {
-        if let Err(e) = ssh_server.start().await {
-            tracing::error!("SSH server error: {}", e);
+    if !args.no_ssh {
+        tracing::info!("SSH Host Key: {:?}", args.ssh_key);
+        tracing::info!("Authorized Keys: {:?}", args.authorized_keys);
+    }
+    tracing::info!("Git Binary: {:?}", args.git_binary);
+    match git::version() {
+        Some(version) => tracing::info!("Git Version: {}", version),
+        None => tracing::warn!("Could not determine the git version; assuming a recent git"),
+    }
+    if args.partial_clone && !git::supports_partial_clone() {
+        tracing::warn!("--partial-clone needs git 2.19 or later; serving full clones only");
+    }
+    if let Some(port) = &args.combined_port {
+        tracing::info!("Combined Port: {}", port);
+    } else {
+        if !args.no_web {
+            tracing::info!("HTTP Port: {}", args.http_port);
         }
-    });
-
-    // Start HTTP server in a task
-    let web_server = web::WebServer::new(args.repos);
-    let http_port = args.http_port.clone();
-    
-    let web_handle = tokio::spawn(async move {
-        if let Err(e) = web_server.start(&http_port).await {
-            tracing::error!("Web server error: {}", e);
+        if !args.no_ssh {
+            tracing::info!("SSH Port: {}", args.ssh_port);
         }
-    });
+    }
 
-    // Wait for shutdown signal
-    match signal::ctrl_c().await {
-        Ok(()) => {
-            tracing::info!("Shutdown signal received");
-        }
-        Err(err) => {
-            tracing::error!("Unable to listen for shutdown signal: {}", err);
-        }
+    let _pid_file = args.pid_file.as_deref().map(PidFile::create).transpose()?;
+
+    let maintenance = Maintenance::new(
+        args.maintenance,
+        args.maintenance_file,
+        args.maintenance_message,
+    );
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let maintenance = maintenance.clone();
+        let mut hangup = signal(SignalKind::hangup())?;
+        tokio::spawn(async move {
+            while hangup.recv().await.is_some() {
+                maintenance.reload();
+            }
+        });
+    }
+
+    let keepalive =
+        (args.ssh_keepalive_interval > 0).then(|| Duration::from_secs(args.ssh_keepalive_interval));
+    let mut server = Server::builder()
+        .repos_dir(args.repos)
+        .bind_addr(args.bind)
+        .ssh_port(args.ssh_port)
+        .http_port(args.http_port)
+        .ssh(!args.no_ssh)
+        .web(!args.no_web)
+        .host_key(args.ssh_key)
+        .authorized_keys(args.authorized_keys)
+        .index_cache_ttl(Duration::from_secs(args.index_cache_ttl))
+        .stale_pack_age((args.stale_pack_age > 0).then(|| Duration::from_secs(args.stale_pack_age)))
+        .maintenance(maintenance)
+        .metrics(args.metrics)
+        .configure_ssh(move |mut ssh| {
+            ssh = ssh
+                .keepalive(keepalive, args.ssh_keepalive_max)
+                .max_channels(args.ssh_max_channels)
+                .io_buffer_size(args.io_buffer_size)
+                .progress_messages(args.ssh_progress)
+                .check_repo_health(args.check_repo_health)
+                .partial_clone(args.partial_clone)
+                .allow_import(args.allow_import)
+                .user_namespaces(args.user_namespaces, args.admin_users)
+                .self_serve(!args.no_self_serve);
+            if let Some(path) = args.motd_file {
+                ssh = ssh.motd_file(path);
+            }
+            if let Some(path) = args.ssh_banner_file {
+                ssh = ssh.banner_file(path);
+            }
+            if let Some(pattern) = args.repo_name_pattern {
+                ssh = ssh.repo_name_pattern(pattern);
+            }
+            if args.max_refs > 0 {
+                ssh = ssh.max_refs(args.max_refs);
+            }
+            ssh
+        })
+        .configure_web(move |mut web| {
+            web = web
+                .max_blob_size(args.max_blob_size)
+                .no_index(args.no_index)
+                .access_log(args.web_access_log.is_some());
+            if let Some(path) = args.web_auth_file {
+                web = web.auth(web_auth::WebAuth::new(path));
+            }
+            if let Some(path) = args.web_acl_file {
+                web = web.acl(web_auth::WebAcl::new(path));
+            }
+            if args.lfs {
+                let store = match args.lfs_dir {
+                    Some(dir) => LfsStore::in_dir(dir),
+                    None => LfsStore::new(),
+                };
+                web = web.lfs(store.max_object_size(args.lfs_max_object_size));
+            }
+            if let Some(url) = args.public_url {
+                web = web.public_url(url);
+            }
+            if let Some(origin) = args.cors_origin {
+                web = web.cors_origin(origin);
+            }
+            if let Some(template) = args.issue_url {
+                web = web.issue_url(template);
+            }
+            if let Some(css) = args.web_css {
+                web = web.theme_css(css);
+            }
+            if let Some(dir) = args.static_dir {
+                web = web.static_dir(dir);
+            }
+            if let (Some(cert), Some(key)) = (args.tls_cert, args.tls_key) {
+                web = web.tls(cert, key);
+            }
+            web
+        });
+    if let Some(port) = args.combined_port {
+        server = server.combined_port(port);
+    }
+    if args.archive_after > 0 {
+        let action = match (args.archive_action.as_str(), args.archive_dir) {
+            ("move", Some(dir)) => ArchiveAction::Move(dir),
+            _ => ArchiveAction::ReadOnly,
+        };
+        let idle_after = Duration::from_secs(args.archive_after * 24 * 60 * 60);
+        server = server.archiver(Archiver::new(idle_after, action));
     }
 
-    tracing::info!("Shutting down...");
-    
-    // In a production system, we'd gracefully shutdown servers here
-    ssh_handle.abort();
-    web_handle.abort();
+    // Registered before serving so an early SIGTERM isn't fatal
+    #[cfg(unix)]
+    let terminate = {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut terminate = signal(SignalKind::terminate())?;
+        async move {
+            terminate.recv().await;
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
 
-    Ok(())
+    // Wait for shutdown signal; supervisors send SIGTERM, terminals SIGINT
+    server
+        .build()
+        .run(async {
+            tokio::select! {
+                result = signal::ctrl_c() => match result {
+                    Ok(()) => {
+                        tracing::info!("Shutdown signal received");
+                    }
+                    Err(err) => {
+                        tracing::error!("Unable to listen for shutdown signal: {}", err);
+                    }
+                },
+                _ = terminate => {
+                    tracing::info!("SIGTERM received");
+                }
+            }
+        })
+        .await
 }
@@ -1,6 +1,6 @@
 use agito::git;
 use std::env;
-use std::process::{Command, exit};
+use std::process::exit;
 
 fn main() {
     let args: Vec<String> = env::args().collect();
@@ -15,7 +15,10 @@ fn main() {
     match command.as_str() {
         "clone" => handle_clone(&args[2..]),
         "create" => handle_create(&args[2..]),
+        "push" | "pull" | "fetch" => handle_sync(&args[1..]),
+        "doctor" => handle_doctor(&args[2..]),
         "help" | "--help" | "-h" => print_usage(),
+        "--version" | "-V" => println!("agito {}", agito::VERSION),
         _ => {
             // Pass through to git for standard git commands
             pass_to_git(&args[1..]);
@@ -32,10 +35,16 @@ Usage:
 Agito Commands:
   clone <url>              Clone a repository from agito server
   create <name>            Create a new bare repository on agito server
+         [--dry-run]       Only check that the repository could be created
+  doctor                   Check that the server is reachable and accepts
+         [--verbose]       your SSH key, and explain what to fix if not
   help                     Show this help message
+  --version, -V            Show version information
 
 Git Commands:
   Any standard git command will be passed through to git
+  push, pull and fetch without a remote use the remote pointing at
+  AGITO_SERVER when the current branch has no upstream
   Examples: agito status, agito commit -m "message", agito push, etc.
 
 Examples:
@@ -63,28 +72,146 @@ fn handle_clone(args: &[String]) {
 }
 
 fn handle_create(args: &[String]) {
-    if args.is_empty() {
-        eprintln!("Error: create requires a repository name");
-        exit(1);
-    }
-
-    let repo_name = &args[0];
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let (repo_name, dry_run) = match args[..] {
+        [name] if !name.starts_with('-') => (name, false),
+        ["--dry-run", name] | [name, "--dry-run"] if !name.starts_with('-') => (name, true),
+        _ => {
+            eprintln!("Usage: agito create [--dry-run] <name>");
+            exit(1);
+        }
+    };
 
     // Get server from environment or use default
     let server = env::var("AGITO_SERVER").unwrap_or_else(|_| "localhost:2222".to_string());
     let user = env::var("AGITO_USER").unwrap_or_else(|_| "git".to_string());
 
-    if let Err(e) = git::create_remote_repo(&server, &user, repo_name) {
+    if let Err(e) = git::create_remote_repo(&server, &user, repo_name, dry_run) {
         eprintln!("Error creating repository: {}", e);
         exit(1);
     }
 
+    if dry_run {
+        return;
+    }
+
     println!("Repository '{}' created successfully on {}", repo_name, server);
-    println!("Clone it with: agito clone ssh://{}@{}/{}", user, server, repo_name);
+
+    // The server stores repositories with a `.git` suffix
+    let repo_name = repo_name.strip_suffix(".git").unwrap_or(repo_name);
+    let url = format!("ssh://{}@{}/{}.git", user, server, repo_name);
+    println!("Clone it with: agito clone {}", url);
+
+    // Creation already succeeded, so a failed check is only worth a warning
+    match git::remote_head(&url) {
+        Ok(Some(branch)) => println!("Default branch: {}", branch),
+        Ok(None) => println!("The repository is reachable and has no commits yet"),
+        Err(e) => eprintln!("Warning: could not reach the new repository: {}", e),
+    }
+}
+
+/// Try to authenticate to the server and explain in plain words what to fix
+/// if that fails
+fn handle_doctor(args: &[String]) {
+    let verbose = args.iter().any(|arg| arg == "--verbose" || arg == "-v");
+    let server = env::var("AGITO_SERVER").unwrap_or_else(|_| "localhost:2222".to_string());
+    let user = env::var("AGITO_USER").unwrap_or_else(|_| "git".to_string());
+    let (host, port) = git::split_server(&server);
+
+    println!("Checking {}@{} (set AGITO_SERVER and AGITO_USER to change)", user, server);
+
+    match git::agent_key_count() {
+        Some(0) => println!("warn  ssh agent: running but has no keys; load one with ssh-add"),
+        Some(count) => println!("ok    ssh agent: {} key(s) loaded", count),
+        None => println!("warn  ssh agent: not running; ssh will only try the key files in ~/.ssh"),
+    }
+
+    let (diagnosis, output) = match git::probe_server(&server, &user) {
+        Ok(result) => result,
+        Err(e) => {
+            println!("FAIL  ssh: {}", e);
+            exit(1);
+        }
+    };
+    if verbose {
+        println!("{}", output.trim_end());
+    }
+
+    let known_hosts_name = if port == "22" {
+        host.to_string()
+    } else {
+        format!("[{}]:{}", host, port)
+    };
+    let problem = match diagnosis {
+        git::SshDiagnosis::Ok => {
+            println!("ok    connection: authenticated to {} as {}", server, user);
+            return;
+        }
+        git::SshDiagnosis::UnresolvedHost => {
+            format!("could not find host '{}'. Check AGITO_SERVER for typos.", host)
+        }
+        git::SshDiagnosis::ConnectionRefused => format!(
+            "nothing is listening on {} port {}. Check that the server is running and that \
+             AGITO_SERVER has its SSH port (2222 unless configured otherwise).",
+            host, port
+        ),
+        git::SshDiagnosis::TimedOut => format!(
+            "no answer from {} port {}. The host may be down, or a firewall may be blocking the port.",
+            host, port
+        ),
+        git::SshDiagnosis::NotSsh => format!(
+            "{} port {} answered, but not as an SSH server. AGITO_SERVER may point at the \
+             web port instead of the SSH port.",
+            host, port
+        ),
+        git::SshDiagnosis::HostKeyUnknown => format!(
+            "{} is not in your known_hosts yet. Connect once with `ssh -p {} {}@{}` and \
+             accept the host key if its fingerprint matches the server's.",
+            server, port, user, host
+        ),
+        git::SshDiagnosis::HostKeyChanged => format!(
+            "the server's host key has changed since you last connected. If the server was \
+             reinstalled or its key replaced, remove the old key with \
+             `ssh-keygen -R '{}'`; otherwise someone may be intercepting the connection.",
+            known_hosts_name
+        ),
+        git::SshDiagnosis::NoKeysOffered => "you have no SSH key to offer. Create one with \
+             `ssh-keygen -t ed25519` (or load yours with ssh-add) and ask the server admin to \
+             add the public key."
+            .to_string(),
+        git::SshDiagnosis::KeysRejected(keys) => format!(
+            "the server rejected your key(s): {}. Ask the server admin to add the matching \
+             .pub file to its authorized keys.",
+            keys.join(", ")
+        ),
+        git::SshDiagnosis::Other(message) => message,
+    };
+
+    println!("FAIL  connection: {}", problem);
+    if !verbose {
+        println!("Run `agito doctor --verbose` to see the full ssh output.");
+    }
+    exit(1);
+}
+
+/// Run `push`/`pull`/`fetch`, naming the agito remote if the user gave none
+fn handle_sync(args: &[String]) {
+    // Only fill in the remote when the command has no positional arguments
+    if args[1..].iter().all(|arg| arg.starts_with('-')) {
+        let server = env::var("AGITO_SERVER").unwrap_or_else(|_| "localhost:2222".to_string());
+        if let Some(remote) = git::agito_remote(&server) {
+            let mut args = args.to_vec();
+            args.push(remote);
+            pass_to_git(&args);
+            return;
+        }
+    }
+
+    pass_to_git(args);
 }
 
 fn pass_to_git(args: &[String]) {
-    let status = Command::new("git")
+    let status = git::git_command()
         .args(args)
         .status()
         .expect("Failed to execute git command");
@@ -0,0 +1,69 @@
+use std::io;
+
+/// Errors returned by agito's repository operations.
+///
+/// The variants separate what a caller can act on: the web layer maps them to
+/// HTTP status codes and the SSH layer to exit codes.
+#[derive(Debug, thiserror::Error)]
+pub enum AgitoError {
+    #[error("Repository not found: {0}")]
+    RepoNotFound(String),
+
+    /// A repository name, branch, revision, hash or other argument is malformed
+    #[error("{0}")]
+    InvalidName(String),
+
+    #[error("{0}")]
+    AccessDenied(String),
+
+    /// A size or count limit would be exceeded
+    #[error("{0}")]
+    QuotaExceeded(String),
+
+    /// Uploaded content doesn't match the hash or size it was declared with
+    #[error("{0}")]
+    ContentMismatch(String),
+
+    /// A git command ran but failed; holds its error output
+    #[error("{0}")]
+    Git(String),
+
+    #[error("{context}: {source}")]
+    Io {
+        context: String,
+        #[source]
+        source: io::Error,
+    },
+}
+
+pub type Result<T, E = AgitoError> = std::result::Result<T, E>;
+
+impl AgitoError {
+    /// Exit status reported to SSH clients, following `sysexits.h`
+    pub fn exit_code(&self) -> u32 {
+        match self {
+            AgitoError::RepoNotFound(_) => 66,  // EX_NOINPUT
+            AgitoError::InvalidName(_) => 65,   // EX_DATAERR
+            AgitoError::AccessDenied(_) => 77,  // EX_NOPERM
+            AgitoError::QuotaExceeded(_) => 73, // EX_CANTCREAT
+            AgitoError::ContentMismatch(_) => 65,
+            AgitoError::Git(_) => 1,
+            AgitoError::Io { .. } => 74, // EX_IOERR
+        }
+    }
+}
+
+/// Attach a description of the failed operation to an I/O error, like
+/// `anyhow::Context` does
+pub trait IoContext<T> {
+    fn io_context(self, context: impl Into<String>) -> Result<T>;
+}
+
+impl<T> IoContext<T> for io::Result<T> {
+    fn io_context(self, context: impl Into<String>) -> Result<T> {
+        self.map_err(|source| AgitoError::Io {
+            context: context.into(),
+            source,
+        })
+    }
+}
@@ -0,0 +1,182 @@
+use std::future::Future;
+use std::io;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// Default size of the buffer used to read from git processes.
+///
+/// Larger reads mean fewer syscalls and channel messages per pack.
+pub const DEFAULT_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Length of the hex length prefix of a pkt-line
+const PKT_HEADER_LEN: usize = 4;
+
+/// Splits a git smart-protocol stream into chunks on pkt-line boundaries.
+///
+/// Each chunk holds only complete packets, and a flush packet (`0000`) always
+/// ends a chunk, so a consumer that frames chunks separately (such as an HTTP
+/// stateless-rpc response) never sees a packet split in two. Once the stream
+/// stops looking like pkt-lines (e.g. a raw packfile sent without side-band),
+/// the remaining data is passed through unchanged.
+#[derive(Default)]
+pub struct PktLineFramer {
+    pending: Vec<u8>,
+    raw: bool,
+}
+
+impl PktLineFramer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Feed data read from the process and return the chunks ready to send
+    pub fn push(&mut self, data: &[u8]) -> Vec<Vec<u8>> {
+        if self.raw {
+            return vec![data.to_vec()];
+        }
+
+        self.pending.extend_from_slice(data);
+
+        let mut chunks = Vec::new();
+        let mut chunk_start = 0;
+        let mut pos = 0;
+
+        while self.pending.len() - pos >= PKT_HEADER_LEN {
+            let Some(len) = parse_pkt_len(&self.pending[pos..pos + PKT_HEADER_LEN]) else {
+                // Not pkt-line framed from here on
+                self.raw = true;
+                if pos > chunk_start {
+                    chunks.push(self.pending[chunk_start..pos].to_vec());
+                }
+                chunks.push(self.pending.split_off(pos));
+                self.pending.clear();
+                return chunks;
+            };
+
+            // Special packets (flush, delim, response-end) are header-only
+            let packet_len = if len < PKT_HEADER_LEN { PKT_HEADER_LEN } else { len };
+            if self.pending.len() - pos < packet_len {
+                break;
+            }
+
+            pos += packet_len;
+
+            if len == 0 {
+                chunks.push(self.pending[chunk_start..pos].to_vec());
+                chunk_start = pos;
+            }
+        }
+
+        if pos > chunk_start {
+            chunks.push(self.pending[chunk_start..pos].to_vec());
+        }
+
+        self.pending.drain(..pos);
+        chunks
+    }
+
+    /// Return any incomplete trailing data once the stream has ended
+    pub fn finish(&mut self) -> Option<Vec<u8>> {
+        if self.pending.is_empty() {
+            None
+        } else {
+            Some(std::mem::take(&mut self.pending))
+        }
+    }
+}
+
+fn parse_pkt_len(header: &[u8]) -> Option<usize> {
+    if !header.iter().all(u8::is_ascii_hexdigit) {
+        return None;
+    }
+    let header = std::str::from_utf8(header).ok()?;
+    usize::from_str_radix(header, 16).ok()
+}
+
+/// Copy a git process's output to `sink`, one pkt-line aligned chunk at a
+/// time, reading up to `buffer_size` bytes at once
+pub async fn copy_pkt_lines<R, F, Fut>(
+    reader: &mut R,
+    buffer_size: usize,
+    mut sink: F,
+) -> io::Result<()>
+where
+    R: AsyncRead + Unpin,
+    F: FnMut(Vec<u8>) -> Fut,
+    Fut: Future<Output = io::Result<()>>,
+{
+    let mut framer = PktLineFramer::new();
+    let mut buf = vec![0u8; buffer_size];
+
+    loop {
+        let n = reader.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+
+        for chunk in framer.push(&buf[..n]) {
+            sink(chunk).await?;
+        }
+    }
+
+    if let Some(rest) = framer.finish() {
+        sink(rest).await?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_chunks_after_flush_packets() {
+        let mut framer = PktLineFramer::new();
+        let chunks = framer.push(b"0009hello0000000aworld!0000");
+        assert_eq!(
+            chunks,
+            vec![b"0009hello0000".to_vec(), b"000aworld!0000".to_vec()]
+        );
+        assert_eq!(framer.finish(), None);
+    }
+
+    #[test]
+    fn holds_back_partial_packets() {
+        let mut framer = PktLineFramer::new();
+        assert_eq!(framer.push(b"0009hel"), Vec::<Vec<u8>>::new());
+        assert_eq!(framer.push(b"lo00"), vec![b"0009hello".to_vec()]);
+        assert_eq!(framer.push(b"00"), vec![b"0000".to_vec()]);
+    }
+
+    #[test]
+    fn treats_delim_and_response_end_as_header_only() {
+        let mut framer = PktLineFramer::new();
+        let chunks = framer.push(b"00010005a0002");
+        assert_eq!(chunks, vec![b"00010005a0002".to_vec()]);
+    }
+
+    #[test]
+    fn passes_through_raw_data() {
+        let mut framer = PktLineFramer::new();
+        let chunks = framer.push(b"0008NAK\nPACK\x00\x00\x00\x02");
+        assert_eq!(
+            chunks,
+            vec![b"0008NAK\n".to_vec(), b"PACK\x00\x00\x00\x02".to_vec()]
+        );
+        assert_eq!(framer.push(b"more"), vec![b"more".to_vec()]);
+    }
+
+    #[tokio::test]
+    async fn copy_pkt_lines_forwards_everything() {
+        let input: &[u8] = b"0009hello0000trailing";
+        let mut reader = input;
+        let mut output = Vec::new();
+        copy_pkt_lines(&mut reader, 4, |chunk| {
+            output.extend(chunk);
+            async { Ok(()) }
+        })
+        .await
+        .unwrap();
+        assert_eq!(output, input);
+    }
+}
@@ -0,0 +1,414 @@
+use crate::error::{AgitoError, IoContext, Result};
+use axum::body::Bytes;
+use data_encoding::HEXLOWER;
+use futures::{Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::io::AsyncWriteExt;
+
+/// Content type of Git LFS batch requests and responses
+pub const LFS_MEDIA_TYPE: &str = "application/vnd.git-lfs+json";
+
+/// The only transfer adapter offered: plain HTTP uploads and downloads
+pub const BASIC_TRANSFER: &str = "basic";
+
+/// The only object hash algorithm supported
+pub const HASH_ALGO: &str = "sha256";
+
+/// Largest object accepted for upload unless configured otherwise: 2 GiB
+pub const DEFAULT_MAX_OBJECT_SIZE: u64 = 2 * 1024 * 1024 * 1024;
+
+/// Where Git LFS objects are kept.
+///
+/// Objects are stored by oid under `lfs/objects` in each repository, the
+/// layout git-lfs itself uses, or under `<dir>/<repository>/objects` when a
+/// separate store is configured.
+#[derive(Clone, Debug)]
+pub struct LfsStore {
+    dir: Option<PathBuf>,
+    max_object_size: u64,
+}
+
+impl Default for LfsStore {
+    fn default() -> Self {
+        Self {
+            dir: None,
+            max_object_size: DEFAULT_MAX_OBJECT_SIZE,
+        }
+    }
+}
+
+impl LfsStore {
+    /// Store objects inside each repository
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Store objects under `dir` instead of inside the repositories
+    pub fn in_dir(dir: PathBuf) -> Self {
+        Self {
+            dir: Some(dir),
+            ..Self::default()
+        }
+    }
+
+    /// Refuse uploads of objects larger than `size` bytes
+    pub fn max_object_size(mut self, size: u64) -> Self {
+        self.max_object_size = size;
+        self
+    }
+
+    /// Root of a repository's LFS storage
+    fn root(&self, repo_path: &Path, repo_name: &str) -> PathBuf {
+        match &self.dir {
+            Some(dir) => dir.join(repo_name),
+            None => repo_path.join("lfs"),
+        }
+    }
+
+    /// Path of an object, which must be a [`valid_oid`]
+    pub fn object_path(&self, repo_path: &Path, repo_name: &str, oid: &str) -> PathBuf {
+        self.root(repo_path, repo_name)
+            .join("objects")
+            .join(&oid[0..2])
+            .join(&oid[2..4])
+            .join(oid)
+    }
+
+    /// Size of a stored object, or `None` if it isn't stored
+    pub fn object_size(&self, repo_path: &Path, repo_name: &str, oid: &str) -> Option<u64> {
+        let metadata = std::fs::metadata(self.object_path(repo_path, repo_name, oid)).ok()?;
+        metadata.is_file().then_some(metadata.len())
+    }
+
+    /// What the client should do with one object of a batch request.
+    ///
+    /// Actions point at `<objects_url>/<oid>` and carry `header`, e.g. the
+    /// client's own credentials, so the transfer is authenticated too.
+    /// Upload actions also carry the declared size, which the upload must
+    /// match.
+    pub fn batch_object(
+        &self,
+        repo_path: &Path,
+        repo_name: &str,
+        operation: Operation,
+        object: ObjectSpec,
+        objects_url: &str,
+        header: &BTreeMap<&'static str, String>,
+    ) -> ObjectResponse {
+        let mut response = ObjectResponse {
+            oid: object.oid,
+            size: object.size,
+            authenticated: true,
+            actions: BTreeMap::new(),
+            error: None,
+        };
+        if !valid_oid(&response.oid) {
+            response.error = Some(ObjectError {
+                code: 422,
+                message: "Invalid object id".to_string(),
+            });
+            return response;
+        }
+
+        let action = Action {
+            href: format!("{}/{}", objects_url, response.oid),
+            header: header.clone(),
+        };
+        match (operation, self.object_size(repo_path, repo_name, &response.oid)) {
+            (Operation::Download, Some(size)) => {
+                response.size = size;
+                response.actions.insert("download", action);
+            }
+            (Operation::Download, None) => {
+                response.error = Some(ObjectError {
+                    code: 404,
+                    message: "Object does not exist".to_string(),
+                });
+            }
+            // Already stored, so there is nothing to upload
+            (Operation::Upload, Some(_)) => {}
+            (Operation::Upload, None) if response.size > self.max_object_size => {
+                response.error = Some(ObjectError {
+                    code: 422,
+                    message: format!(
+                        "Object is larger than this server accepts ({} bytes)",
+                        self.max_object_size
+                    ),
+                });
+            }
+            (Operation::Upload, None) => {
+                let action = Action {
+                    href: format!("{}?size={}", action.href, response.size),
+                    ..action
+                };
+                response.actions.insert("upload", action);
+            }
+        }
+        response
+    }
+
+    /// Store an uploaded object of the declared `size`.
+    ///
+    /// The content is written to a temporary file and only moved into place
+    /// once its length is `size` and its SHA-256 matches `oid`, so a failed
+    /// or forged upload never replaces a good object. Uploads over the
+    /// maximum object size are cut off as soon as they pass it.
+    pub async fn write_object<S, E>(
+        &self,
+        repo_path: &Path,
+        repo_name: &str,
+        oid: &str,
+        size: u64,
+        mut body: S,
+    ) -> Result<()>
+    where
+        S: Stream<Item = std::result::Result<Bytes, E>> + Unpin,
+        E: std::fmt::Display,
+    {
+        static UPLOADS: AtomicU64 = AtomicU64::new(0);
+
+        if size > self.max_object_size {
+            return Err(AgitoError::QuotaExceeded(format!(
+                "Object is larger than this server accepts ({} bytes)",
+                self.max_object_size
+            )));
+        }
+
+        let tmp_dir = self.root(repo_path, repo_name).join("tmp");
+        tokio::fs::create_dir_all(&tmp_dir)
+            .await
+            .io_context("Failed to create LFS temporary directory")?;
+        let tmp_path = tmp_dir.join(format!(
+            "{}-{}-{}",
+            oid,
+            std::process::id(),
+            UPLOADS.fetch_add(1, Ordering::Relaxed)
+        ));
+
+        if let Err(e) = write_verified(&tmp_path, oid, size, &mut body).await {
+            let _ = tokio::fs::remove_file(&tmp_path).await;
+            return Err(e);
+        }
+
+        let path = self.object_path(repo_path, repo_name, oid);
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent)
+                .await
+                .io_context("Failed to create LFS object directory")?;
+        }
+        tokio::fs::rename(&tmp_path, &path)
+            .await
+            .io_context("Failed to store LFS object")?;
+        Ok(())
+    }
+}
+
+/// Write `body` to `path`, failing unless it is `expected` bytes long and
+/// hashes to `oid`
+async fn write_verified<S, E>(path: &Path, oid: &str, expected: u64, body: &mut S) -> Result<()>
+where
+    S: Stream<Item = std::result::Result<Bytes, E>> + Unpin,
+    E: std::fmt::Display,
+{
+    let mut file = tokio::fs::File::create(path)
+        .await
+        .io_context("Failed to create LFS temporary file")?;
+    let mut hasher = Sha256::new();
+    let mut size = 0;
+    while let Some(chunk) = body.next().await {
+        let chunk = chunk.map_err(|e| AgitoError::Io {
+            context: "Failed to read LFS upload".to_string(),
+            source: std::io::Error::other(e.to_string()),
+        })?;
+        hasher.update(&chunk);
+        size += chunk.len() as u64;
+        if size > expected {
+            return Err(AgitoError::ContentMismatch(format!(
+                "Upload is longer than the declared {} bytes",
+                expected
+            )));
+        }
+        file.write_all(&chunk)
+            .await
+            .io_context("Failed to write LFS object")?;
+    }
+    file.flush().await.io_context("Failed to write LFS object")?;
+
+    if size != expected {
+        return Err(AgitoError::ContentMismatch(format!(
+            "Upload is {} bytes, not the declared {}",
+            size, expected
+        )));
+    }
+    let actual = HEXLOWER.encode(&hasher.finalize());
+    if actual != oid {
+        return Err(AgitoError::ContentMismatch(format!(
+            "Uploaded content has oid {}, not {}",
+            actual, oid
+        )));
+    }
+    Ok(())
+}
+
+/// Whether `oid` is a SHA-256 object id as git-lfs sends them: 64 lowercase
+/// hex digits
+pub fn valid_oid(oid: &str) -> bool {
+    oid.len() == 64 && oid.bytes().all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+}
+
+/// Body of `POST .../info/lfs/objects/batch`
+#[derive(Debug, Deserialize)]
+pub struct BatchRequest {
+    pub operation: Operation,
+    #[serde(default)]
+    pub transfers: Vec<String>,
+    #[serde(default)]
+    pub hash_algo: Option<String>,
+    pub objects: Vec<ObjectSpec>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Operation {
+    Download,
+    Upload,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ObjectSpec {
+    pub oid: String,
+    pub size: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BatchResponse {
+    pub transfer: &'static str,
+    pub objects: Vec<ObjectResponse>,
+    pub hash_algo: &'static str,
+}
+
+/// One object of a [`BatchResponse`]. Without `actions` or `error` there is
+/// nothing to do, e.g. for an upload the server already has.
+#[derive(Debug, Serialize)]
+pub struct ObjectResponse {
+    pub oid: String,
+    pub size: u64,
+    pub authenticated: bool,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub actions: BTreeMap<&'static str, Action>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<ObjectError>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Action {
+    pub href: String,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub header: BTreeMap<&'static str, String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ObjectError {
+    pub code: u16,
+    pub message: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn write_object_verifies_content() {
+        let root = std::env::temp_dir().join(format!("agito-lfs-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        let repo = root.join("repo.git");
+        let store = LfsStore::new();
+
+        let content = b"hello lfs\n";
+        let oid = HEXLOWER.encode(&Sha256::digest(content));
+        assert!(valid_oid(&oid));
+        let chunks = |data: &'static [u8]| {
+            futures::stream::iter(
+                data.chunks(4)
+                    .map(|chunk| Ok::<_, std::io::Error>(Bytes::from_static(chunk))),
+            )
+        };
+
+        let size = content.len() as u64;
+        let write = |store: &LfsStore, size, body| {
+            let (store, repo, oid) = (store.clone(), repo.clone(), oid.clone());
+            async move { store.write_object(&repo, "repo.git", &oid, size, body).await }
+        };
+        let forged = write(&store, 6, chunks(b"not it")).await;
+        assert!(matches!(forged, Err(AgitoError::ContentMismatch(_))));
+        let short = write(&store, size + 1, chunks(content)).await;
+        assert!(matches!(short, Err(AgitoError::ContentMismatch(_))));
+        let long = write(&store, size - 1, chunks(content)).await;
+        assert!(matches!(long, Err(AgitoError::ContentMismatch(_))));
+        let capped = write(&store.clone().max_object_size(4), size, chunks(content)).await;
+        assert!(matches!(capped, Err(AgitoError::QuotaExceeded(_))));
+        assert_eq!(store.object_size(&repo, "repo.git", &oid), None);
+        assert_eq!(std::fs::read_dir(repo.join("lfs/tmp")).unwrap().count(), 0);
+
+        write(&store, size, chunks(content)).await.unwrap();
+        assert_eq!(store.object_size(&repo, "repo.git", &oid), Some(size));
+        let stored = repo.join("lfs/objects").join(&oid[0..2]).join(&oid[2..4]).join(&oid);
+        assert_eq!(std::fs::read(stored).unwrap(), content);
+
+        let elsewhere = LfsStore::in_dir(root.join("store"));
+        let path = elsewhere.object_path(&repo, "alice/repo.git", &oid);
+        assert!(path.starts_with(root.join("store/alice/repo.git/objects")));
+
+        let _ = std::fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn batch_objects_follow_the_store() {
+        let root = std::env::temp_dir().join(format!("agito-lfs-batch-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        let store = LfsStore::in_dir(root.clone()).max_object_size(10);
+
+        let stored = "a".repeat(64);
+        let missing = "b".repeat(64);
+        let path = store.object_path(&root, "repo.git", &stored);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, "12345").unwrap();
+
+        let url = "http://git.example/repo/repo.git/info/lfs/objects";
+        let header = BTreeMap::from([("Authorization", "Basic eDp5".to_string())]);
+        let batch = |operation, oid: &str| {
+            let object = ObjectSpec {
+                oid: oid.to_string(),
+                size: if oid == "c".repeat(64) { 11 } else { 5 },
+            };
+            let response = store.batch_object(&root, "repo.git", operation, object, url, &header);
+            serde_json::to_value(response).unwrap()
+        };
+
+        let download = batch(Operation::Download, &stored);
+        assert_eq!(download["actions"]["download"]["href"], format!("{}/{}", url, stored));
+        assert_eq!(download["actions"]["download"]["header"]["Authorization"], "Basic eDp5");
+        assert_eq!(batch(Operation::Download, &missing)["error"]["code"], 404);
+        assert_eq!(batch(Operation::Download, "../x")["error"]["code"], 422);
+
+        // Objects the server has already are not uploaded again
+        assert!(batch(Operation::Upload, &stored).get("actions").is_none());
+        let upload = batch(Operation::Upload, &missing);
+        assert_eq!(upload["actions"]["upload"]["href"], format!("{}/{}?size=5", url, missing));
+        assert_eq!(batch(Operation::Upload, &"c".repeat(64))["error"]["code"], 422);
+
+        let _ = std::fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn valid_oid_is_sha256_hex() {
+        assert!(valid_oid(&"a".repeat(64)));
+        assert!(!valid_oid(&"A".repeat(64)));
+        assert!(!valid_oid(&"a".repeat(63)));
+        assert!(!valid_oid("../../../../etc/passwd"));
+    }
+}
@@ -1,3 +1,19 @@
+pub mod archive;
+pub mod error;
 pub mod git;
+pub mod git_io;
+pub mod hooks;
+pub mod lfs;
+pub mod maintenance;
+pub mod metrics;
+pub mod mux;
+pub mod net;
+pub mod server;
 pub mod ssh;
 pub mod web;
+pub mod web_auth;
+
+pub use server::Server;
+
+/// Crate version plus the git commit it was built from
+pub const VERSION: &str = concat!(env!("CARGO_PKG_VERSION"), " (", env!("AGITO_GIT_COMMIT"), ")");
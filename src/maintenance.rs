@@ -0,0 +1,59 @@
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// Banner shown when no message is configured
+pub const DEFAULT_MESSAGE: &str = "Server in maintenance mode, pushes disabled";
+
+/// Shared maintenance switch, read by the web server for its banner and by
+/// the SSH server to refuse pushes.
+///
+/// Maintenance is on when it was requested at startup or while the marker
+/// file exists. The marker is checked at startup and on every [`reload`].
+///
+/// [`reload`]: Maintenance::reload
+#[derive(Clone)]
+pub struct Maintenance {
+    forced: bool,
+    marker: Option<PathBuf>,
+    message: Arc<String>,
+    enabled: Arc<AtomicBool>,
+}
+
+impl Maintenance {
+    pub fn new(forced: bool, marker: Option<PathBuf>, message: String) -> Self {
+        let maintenance = Self {
+            forced,
+            marker,
+            message: Arc::new(message),
+            enabled: Arc::new(AtomicBool::new(forced)),
+        };
+        maintenance.reload();
+        maintenance
+    }
+
+    /// Not in maintenance, for servers that don't use it
+    pub fn disabled() -> Self {
+        Self::new(false, None, DEFAULT_MESSAGE.to_string())
+    }
+
+    /// Re-check the marker file, e.g. after SIGHUP
+    pub fn reload(&self) {
+        let marked = self.marker.as_ref().is_some_and(|marker| marker.exists());
+        let enabled = self.forced || marked;
+        if self.enabled.swap(enabled, Ordering::Relaxed) != enabled {
+            tracing::info!(
+                "Maintenance mode {}",
+                if enabled { "enabled" } else { "disabled" }
+            );
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+}
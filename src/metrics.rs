@@ -0,0 +1,114 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+
+/// Running totals of the git transfers served over SSH, shared between the
+/// SSH server, which records every finished transfer, and the web server,
+/// which renders them at `/metrics`.
+///
+/// Totals start at zero with each process and cover only SSH; the web
+/// server serves no git transfers of its own.
+#[derive(Clone, Default)]
+pub struct TransferStats {
+    services: Arc<Mutex<BTreeMap<String, ServiceTotals>>>,
+}
+
+/// Totals for one git service, e.g. `upload-pack`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ServiceTotals {
+    pub transfers: u64,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+}
+
+impl TransferStats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Count a finished run of `service` that sent `sent` bytes to the
+    /// client and received `received` from it
+    pub fn record(&self, service: &str, sent: u64, received: u64) {
+        let mut services = self.services.lock().unwrap();
+        let totals = services.entry(service.to_string()).or_default();
+        totals.transfers += 1;
+        totals.bytes_sent += sent;
+        totals.bytes_received += received;
+    }
+
+    /// Totals so far for `service`
+    pub fn totals(&self, service: &str) -> ServiceTotals {
+        let services = self.services.lock().unwrap();
+        services.get(service).copied().unwrap_or_default()
+    }
+
+    /// The totals in the Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let services = self.services.lock().unwrap().clone();
+        let mut out = String::new();
+        write_counter(
+            &mut out,
+            "agito_ssh_transfers_total",
+            "Git transfers over SSH that have finished",
+            &services,
+            |totals| totals.transfers,
+        );
+        write_counter(
+            &mut out,
+            "agito_ssh_bytes_sent_total",
+            "Bytes of git output sent to SSH clients",
+            &services,
+            |totals| totals.bytes_sent,
+        );
+        write_counter(
+            &mut out,
+            "agito_ssh_bytes_received_total",
+            "Bytes received from SSH clients for git",
+            &services,
+            |totals| totals.bytes_received,
+        );
+        out
+    }
+}
+
+/// One counter with a sample per service
+fn write_counter(
+    out: &mut String,
+    name: &str,
+    help: &str,
+    services: &BTreeMap<String, ServiceTotals>,
+    value: fn(&ServiceTotals) -> u64,
+) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} counter", name);
+    for (service, totals) in services {
+        let _ = writeln!(out, "{}{{service=\"{}\"}} {}", name, service, value(totals));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn totals_add_up_per_service() {
+        let stats = TransferStats::new();
+        stats.record("upload-pack", 100, 10);
+        stats.record("upload-pack", 50, 5);
+        stats.record("receive-pack", 1, 200);
+
+        let expected = ServiceTotals {
+            transfers: 2,
+            bytes_sent: 150,
+            bytes_received: 15,
+        };
+        assert_eq!(stats.totals("upload-pack"), expected);
+        assert_eq!(stats.totals("upload-archive"), ServiceTotals::default());
+
+        let text = stats.render();
+        assert!(text.contains("# TYPE agito_ssh_bytes_sent_total counter\n"));
+        assert!(text.contains("agito_ssh_bytes_sent_total{service=\"upload-pack\"} 150\n"));
+        assert!(text.contains("agito_ssh_bytes_received_total{service=\"receive-pack\"} 200\n"));
+        assert!(text.contains("agito_ssh_transfers_total{service=\"receive-pack\"} 1\n"));
+    }
+}
@@ -0,0 +1,77 @@
+use crate::{net, ssh};
+use anyhow::Result;
+use axum::Router;
+use hyper::server::conn::http1;
+use hyper_util::rt::TokioIo;
+use hyper_util::service::TowerToHyperService;
+use std::net::IpAddr;
+use std::time::Duration;
+use tokio::net::TcpStream;
+
+/// How long to wait for the client to speak first before assuming SSH
+const SNIFF_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Serve SSH and HTTP on a single port.
+///
+/// SSH clients send their `SSH-2.0-...` identification string as soon as they
+/// connect, so the first bytes of each connection are peeked to decide which
+/// server handles it. Connections that stay silent are handed to SSH, which
+/// sends the server banner first.
+pub async fn serve_combined(
+    bind_addr: IpAddr,
+    port: &str,
+    ssh: ssh::Acceptor,
+    web: Router,
+) -> Result<()> {
+    let listener = net::bind(bind_addr, port)?;
+    tracing::info!("Combined SSH/HTTP listener on {}", listener.local_addr()?);
+
+    loop {
+        let (stream, _addr) = listener.accept().await?;
+        let ssh = ssh.clone();
+        let web = web.clone();
+
+        tokio::spawn(async move {
+            if is_ssh(&stream).await {
+                ssh.accept(stream);
+            } else {
+                serve_http(stream, web).await;
+            }
+        });
+    }
+}
+
+async fn is_ssh(stream: &TcpStream) -> bool {
+    const PREFIX: &[u8] = b"SSH-";
+
+    let sniff = async {
+        let mut buf = [0u8; PREFIX.len()];
+        loop {
+            let n = stream.peek(&mut buf).await?;
+            // Keep peeking while a short read could still turn into the prefix
+            if n == 0 || n == PREFIX.len() || !PREFIX.starts_with(&buf[..n]) {
+                return Ok::<_, std::io::Error>(&buf[..n] == PREFIX);
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    };
+
+    match tokio::time::timeout(SNIFF_TIMEOUT, sniff).await {
+        Ok(Ok(is_ssh)) => is_ssh,
+        Ok(Err(_)) => false,
+        Err(_) => true,
+    }
+}
+
+async fn serve_http(stream: TcpStream, web: Router) {
+    let io = TokioIo::new(stream);
+    let service = TowerToHyperService::new(web);
+
+    if let Err(e) = http1::Builder::new()
+        .serve_connection(io, service)
+        .with_upgrades()
+        .await
+    {
+        tracing::debug!("HTTP connection error: {}", e);
+    }
+}
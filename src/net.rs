@@ -0,0 +1,167 @@
+use anyhow::{Context, Result};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::net::TcpListener;
+
+/// Default bind address: every interface, on both IPv6 and IPv4
+pub const DEFAULT_BIND_ADDR: IpAddr = IpAddr::V6(Ipv6Addr::UNSPECIFIED);
+
+/// Bind a TCP listener on `addr:port`.
+///
+/// An IPv6 address is bound dual-stack (with `IPV6_V6ONLY` off), so `::`
+/// accepts both IPv6 and IPv4 clients. If the host has no IPv6 support, `::`
+/// falls back to `0.0.0.0`.
+pub fn bind(addr: IpAddr, port: &str) -> Result<TcpListener> {
+    let port: u16 = port.parse().with_context(|| format!("Invalid port: {}", port))?;
+
+    match bind_socket(SocketAddr::new(addr, port)) {
+        Ok(listener) => Ok(listener),
+        Err(e) if addr == DEFAULT_BIND_ADDR && !is_port_error(&e) => {
+            tracing::warn!("IPv6 unavailable ({}), listening on IPv4 only", e);
+            bind_socket(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), port))
+                .with_context(|| format!("Failed to bind 0.0.0.0:{}", port))
+        }
+        Err(e) => {
+            Err(e).with_context(|| format!("Failed to bind {}", SocketAddr::new(addr, port)))
+        }
+    }
+}
+
+/// Whether `ip` is a public internet address. Loopback, private, shared
+/// (CGNAT), link-local, unique local, multicast, documentation and
+/// unspecified addresses are not, nor are IPv6 addresses embedding an IPv4
+/// address that would be one of them.
+pub fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_multicast()
+                || ip.is_documentation()
+                || a == 0
+                || (a == 100 && (64..128).contains(&b))
+                || a >= 240)
+        }
+        IpAddr::V6(ip) => {
+            if let Some(v4) = embedded_ipv4(ip) {
+                return is_public(IpAddr::V4(v4));
+            }
+            let [first, second, third, ..] = ip.segments();
+            !(ip.is_loopback()
+                || ip.is_unspecified()
+                || ip.is_multicast()
+                || (first & 0xfe00) == 0xfc00
+                || (first & 0xffc0) == 0xfe80
+                // Documentation, 2001:db8::/32
+                || (first == 0x2001 && second == 0x0db8)
+                // Local-use NAT64, 64:ff9b:1::/48, which may translate to
+                // any IPv4 address
+                || (first == 0x64 && second == 0xff9b && third == 1))
+        }
+    }
+}
+
+/// The IPv4 address an IPv6 one stands for: IPv4-mapped (`::ffff:0:0/96`),
+/// IPv4-compatible (`::/96`), well-known NAT64 (`64:ff9b::/96`) and 6to4
+/// (`2002::/16`, with the address in bits 16 to 48)
+fn embedded_ipv4(ip: Ipv6Addr) -> Option<Ipv4Addr> {
+    if let Some(v4) = ip.to_ipv4_mapped() {
+        return Some(v4);
+    }
+    let octets = ip.octets();
+    let low = Ipv4Addr::new(octets[12], octets[13], octets[14], octets[15]);
+    match ip.segments() {
+        // `::` and `::1` are IPv6's own unspecified and loopback addresses
+        [0, 0, 0, 0, 0, 0, 0, 0 | 1] => None,
+        [0, 0, 0, 0, 0, 0, _, _] | [0x64, 0xff9b, 0, 0, 0, 0, _, _] => Some(low),
+        [0x2002, ..] => Some(Ipv4Addr::new(octets[2], octets[3], octets[4], octets[5])),
+        _ => None,
+    }
+}
+
+/// Errors that would equally affect an IPv4 bind of the same port
+fn is_port_error(e: &std::io::Error) -> bool {
+    matches!(
+        e.kind(),
+        std::io::ErrorKind::AddrInUse | std::io::ErrorKind::PermissionDenied
+    )
+}
+
+fn bind_socket(addr: SocketAddr) -> std::io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(false)?;
+    }
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+
+    TcpListener::from_std(socket.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpStream;
+
+    #[tokio::test]
+    async fn dual_stack_accepts_ipv4_and_ipv6() {
+        let listener = bind(DEFAULT_BIND_ADDR, "0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        for client in ["127.0.0.1", "::1"] {
+            let client: IpAddr = client.parse().unwrap();
+            let connect = TcpStream::connect(SocketAddr::new(client, port));
+            let (connected, accepted) = tokio::join!(connect, listener.accept());
+            connected.unwrap();
+            accepted.unwrap();
+        }
+    }
+
+    #[test]
+    fn is_public_rejects_internal_addresses() {
+        for ip in [
+            "93.184.216.34",
+            "2606:2800:220:1::1",
+            "::ffff:93.184.216.34",
+            "64:ff9b::5db8:d822",
+            "2002:5db8:d822::1",
+        ] {
+            assert!(is_public(ip.parse().unwrap()), "{}", ip);
+        }
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "::1",
+            "::",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+            "::10.0.0.1",
+            "64:ff9b::a9fe:a9fe",
+            "64:ff9b::127.0.0.1",
+            "64:ff9b:1::5db8:d822",
+            "2002:a9fe:a9fe::1",
+            "2002:c0a8:101::",
+            "2001:db8::1",
+        ] {
+            assert!(!is_public(ip.parse().unwrap()), "{}", ip);
+        }
+    }
+
+    #[tokio::test]
+    async fn explicit_ipv4_bind() {
+        let listener = bind(IpAddr::V4(Ipv4Addr::LOCALHOST), "0").unwrap();
+        assert!(listener.local_addr().unwrap().is_ipv4());
+    }
+}
@@ -0,0 +1,340 @@
+use crate::archive::{self, Archiver};
+use crate::maintenance::Maintenance;
+use crate::metrics::TransferStats;
+use crate::{git, mux, net, ssh, web};
+use anyhow::{Context, Result};
+use std::fs;
+use std::future::Future;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::task::JoinSet;
+
+type ConfigureSsh = Box<dyn FnOnce(ssh::Server) -> ssh::Server + Send>;
+type ConfigureWeb = Box<dyn FnOnce(web::WebServer) -> web::WebServer + Send>;
+
+/// The SSH and web servers over one repository directory, run together.
+///
+/// This is what `agito-server` runs; applications can embed it instead of
+/// running the binary:
+///
+/// ```no_run
+/// # async fn example() -> anyhow::Result<()> {
+/// let (shutdown_tx, shutdown_rx) = tokio::sync::oneshot::channel::<()>();
+/// let server = agito::Server::builder()
+///     .repos_dir("/srv/git".into())
+///     .ssh_port("2222".to_string())
+///     .build();
+/// let running = tokio::spawn(server.run(shutdown_rx));
+///
+/// // ... later
+/// let _ = shutdown_tx.send(());
+/// running.await??;
+/// # Ok(())
+/// # }
+/// ```
+pub struct Server {
+    repos_dir: PathBuf,
+    host_key_path: PathBuf,
+    bind_addr: IpAddr,
+    http_port: String,
+    combined_port: Option<String>,
+    stale_pack_age: Option<Duration>,
+    archiver: Option<Archiver>,
+    index_cache: web::IndexCache,
+    ssh_enabled: bool,
+    web_enabled: bool,
+    ssh: ssh::Server,
+    web: web::WebServer,
+}
+
+pub struct ServerBuilder {
+    repos_dir: PathBuf,
+    bind_addr: IpAddr,
+    ssh_port: String,
+    http_port: String,
+    combined_port: Option<String>,
+    ssh_enabled: bool,
+    web_enabled: bool,
+    host_key_path: PathBuf,
+    authorized_keys_path: PathBuf,
+    index_cache_ttl: Duration,
+    stale_pack_age: Option<Duration>,
+    archiver: Option<Archiver>,
+    maintenance: Maintenance,
+    metrics: bool,
+    configure_ssh: Option<ConfigureSsh>,
+    configure_web: Option<ConfigureWeb>,
+}
+
+impl Server {
+    pub fn builder() -> ServerBuilder {
+        ServerBuilder {
+            repos_dir: PathBuf::from("/var/lib/agito/repos"),
+            bind_addr: net::DEFAULT_BIND_ADDR,
+            ssh_port: "2222".to_string(),
+            http_port: "3000".to_string(),
+            combined_port: None,
+            ssh_enabled: true,
+            web_enabled: true,
+            host_key_path: PathBuf::from("/var/lib/agito/ssh/host_key"),
+            authorized_keys_path: PathBuf::from("/var/lib/agito/ssh/authorized_keys"),
+            index_cache_ttl: Duration::from_secs(30),
+            stale_pack_age: Some(ssh::DEFAULT_STALE_PACK_AGE),
+            archiver: None,
+            maintenance: Maintenance::disabled(),
+            metrics: false,
+            configure_ssh: None,
+            configure_web: None,
+        }
+    }
+
+    /// Serve until `shutdown` completes or a server fails.
+    ///
+    /// On shutdown the listeners are closed; SSH sessions already in progress
+    /// are left to finish.
+    pub async fn run(self, shutdown: impl Future) -> Result<()> {
+        if !self.ssh_enabled && !self.web_enabled {
+            anyhow::bail!("Both the SSH and web servers are disabled; enable at least one");
+        }
+        if self.combined_port.is_some() && !(self.ssh_enabled && self.web_enabled) {
+            anyhow::bail!("A combined port needs both the SSH and web servers enabled");
+        }
+
+        fs::create_dir_all(&self.repos_dir)
+            .with_context(|| format!("Failed to create {:?}", self.repos_dir))?;
+        if self.ssh_enabled {
+            if let Some(parent) = self.host_key_path.parent() {
+                fs::create_dir_all(parent)
+                    .with_context(|| format!("Failed to create {:?}", parent))?;
+            }
+        }
+
+        if let Some(age) = self.stale_pack_age {
+            let removed = git::clean_all_stale_packs(&self.repos_dir, age);
+            if removed > 0 {
+                tracing::info!("Removed {} stale pack file(s)", removed);
+            }
+        }
+
+        // Dropped, and so stopped, when the servers stop
+        let mut background = JoinSet::new();
+        if let Some(archiver) = self.archiver {
+            let repos_dir = self.repos_dir.clone();
+            let index_cache = self.index_cache.clone();
+            background.spawn(async move {
+                let mut interval = tokio::time::interval(archive::ARCHIVE_INTERVAL);
+                loop {
+                    interval.tick().await;
+                    let archiver = archiver.clone();
+                    let repos_dir = repos_dir.clone();
+                    let scan = tokio::task::spawn_blocking(move || {
+                        archiver.run_once(&repos_dir, chrono::Utc::now())
+                    });
+                    match scan.await {
+                        Ok(0) => {}
+                        Ok(archived) => {
+                            tracing::info!("Archived {} idle repositories", archived);
+                            index_cache.invalidate();
+                        }
+                        Err(e) => tracing::warn!("Archive scan failed: {}", e),
+                    }
+                }
+            });
+        }
+
+        let bind_addr = self.bind_addr;
+        let ssh = self.ssh;
+        let web = self.web;
+        let mut servers = JoinSet::new();
+        match self.combined_port {
+            Some(port) => {
+                // Serve both protocols from one listener
+                servers.spawn(async move {
+                    let acceptor = ssh.acceptor().await?;
+                    mux::serve_combined(bind_addr, &port, acceptor, web.router())
+                        .await
+                        .context("Combined server error")
+                });
+            }
+            None => {
+                let http_port = self.http_port;
+                if self.ssh_enabled {
+                    servers.spawn(async move { ssh.start().await.context("SSH server error") });
+                }
+                if self.web_enabled {
+                    servers.spawn(async move {
+                        web.start(bind_addr, &http_port)
+                            .await
+                            .context("Web server error")
+                    });
+                }
+            }
+        }
+
+        // Dropping the set aborts the server tasks that are still running
+        tokio::select! {
+            _ = shutdown => {
+                tracing::info!("Shutting down...");
+                Ok(())
+            }
+            Some(result) = servers.join_next() => result?,
+        }
+    }
+}
+
+impl ServerBuilder {
+    /// Directory holding the repositories; created if missing
+    pub fn repos_dir(mut self, path: PathBuf) -> Self {
+        self.repos_dir = path;
+        self
+    }
+
+    /// Listen on a specific address instead of all interfaces
+    pub fn bind_addr(mut self, addr: IpAddr) -> Self {
+        self.bind_addr = addr;
+        self
+    }
+
+    pub fn ssh_port(mut self, port: String) -> Self {
+        self.ssh_port = port;
+        self
+    }
+
+    pub fn http_port(mut self, port: String) -> Self {
+        self.http_port = port;
+        self
+    }
+
+    /// Serve SSH and HTTP together on this port instead of the separate ones
+    pub fn combined_port(mut self, port: String) -> Self {
+        self.combined_port = Some(port);
+        self
+    }
+
+    /// Whether to run the SSH server; without it the host key isn't needed
+    pub fn ssh(mut self, enabled: bool) -> Self {
+        self.ssh_enabled = enabled;
+        self
+    }
+
+    /// Whether to run the web server
+    pub fn web(mut self, enabled: bool) -> Self {
+        self.web_enabled = enabled;
+        self
+    }
+
+    /// SSH host key, generated there if it doesn't exist
+    pub fn host_key(mut self, path: PathBuf) -> Self {
+        self.host_key_path = path;
+        self
+    }
+
+    pub fn authorized_keys(mut self, path: PathBuf) -> Self {
+        self.authorized_keys_path = path;
+        self
+    }
+
+    /// How long the web index is cached; zero disables caching
+    pub fn index_cache_ttl(mut self, ttl: Duration) -> Self {
+        self.index_cache_ttl = ttl;
+        self
+    }
+
+    /// Age at which temporary pack files count as left over from an
+    /// interrupted push. `None` skips the scan at startup and leaves
+    /// `agito-clean` at its default.
+    pub fn stale_pack_age(mut self, age: Option<Duration>) -> Self {
+        self.stale_pack_age = age;
+        self
+    }
+
+    /// Periodically archive repositories that have been idle too long
+    pub fn archiver(mut self, archiver: Archiver) -> Self {
+        self.archiver = Some(archiver);
+        self
+    }
+
+    /// Maintenance switch shared by both servers
+    pub fn maintenance(mut self, maintenance: Maintenance) -> Self {
+        self.maintenance = maintenance;
+        self
+    }
+
+    /// Serve totals of the SSH server's git transfers at `/metrics` on the
+    /// web server
+    pub fn metrics(mut self, enabled: bool) -> Self {
+        self.metrics = enabled;
+        self
+    }
+
+    /// Apply further settings to the SSH server, replacing any earlier call
+    pub fn configure_ssh(
+        mut self,
+        configure: impl FnOnce(ssh::Server) -> ssh::Server + Send + 'static,
+    ) -> Self {
+        self.configure_ssh = Some(Box::new(configure));
+        self
+    }
+
+    /// Apply further settings to the web server, replacing any earlier call
+    pub fn configure_web(
+        mut self,
+        configure: impl FnOnce(web::WebServer) -> web::WebServer + Send + 'static,
+    ) -> Self {
+        self.configure_web = Some(Box::new(configure));
+        self
+    }
+
+    pub fn build(self) -> Server {
+        // Shared between both servers so SSH repo creation can invalidate it
+        let index_cache = web::IndexCache::new(self.index_cache_ttl);
+        // And so pushes over SSH reach the web event streams
+        let ref_events = web::RefEvents::new();
+        // And so SSH transfers are counted at /metrics
+        let transfer_stats = TransferStats::new();
+
+        let mut ssh = ssh::Server::new(
+            self.ssh_port,
+            self.host_key_path.clone(),
+            self.authorized_keys_path,
+            self.repos_dir.clone(),
+            index_cache.clone(),
+        )
+        .bind_addr(self.bind_addr)
+        .ref_events(ref_events.clone())
+        .transfer_stats(transfer_stats.clone())
+        .maintenance(self.maintenance.clone());
+        if let Some(age) = self.stale_pack_age {
+            ssh = ssh.stale_pack_age(age);
+        }
+        if let Some(configure) = self.configure_ssh {
+            ssh = configure(ssh);
+        }
+
+        let mut web = web::WebServer::new(self.repos_dir.clone(), index_cache.clone())
+            .ref_events(ref_events)
+            .maintenance(self.maintenance);
+        if self.metrics {
+            web = web.transfer_stats(transfer_stats);
+        }
+        if let Some(configure) = self.configure_web {
+            web = configure(web);
+        }
+
+        Server {
+            repos_dir: self.repos_dir,
+            host_key_path: self.host_key_path,
+            bind_addr: self.bind_addr,
+            http_port: self.http_port,
+            combined_port: self.combined_port,
+            stale_pack_age: self.stale_pack_age,
+            archiver: self.archiver,
+            index_cache,
+            ssh_enabled: self.ssh_enabled,
+            web_enabled: self.web_enabled,
+            ssh,
+            web,
+        }
+    }
+}
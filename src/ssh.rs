@@ -1,20 +1,57 @@
+use crate::error::{AgitoError, IoContext};
+use crate::maintenance::Maintenance;
+use crate::metrics::TransferStats;
+use crate::web::{IndexCache, RefEvents};
+use crate::{git_io, net};
 use anyhow::{Context, Result};
 use async_trait::async_trait;
 use russh::server::{Auth, Msg, Session};
 use russh::{Channel, ChannelId};
+use regex::Regex;
 use russh_keys::key;
+use russh_keys::PublicKeyBase64;
+use std::collections::{HashMap, HashSet};
 use std::fs;
-use std::path::PathBuf;
+use std::io;
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
 use std::process::Stdio;
-use std::sync::Arc;
-use tokio::io::AsyncReadExt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
 use tokio::process::Command;
+use tokio::sync::mpsc;
+use tracing::Instrument;
 
 pub struct Server {
     port: String,
     host_key_path: PathBuf,
     authorized_keys_path: PathBuf,
     repos_dir: PathBuf,
+    index_cache: IndexCache,
+    ref_events: RefEvents,
+    transfer_stats: TransferStats,
+    keepalive_interval: Option<Duration>,
+    keepalive_max: usize,
+    max_channels: usize,
+    bind_addr: IpAddr,
+    progress: bool,
+    check_health: bool,
+    partial_clone: bool,
+    allow_import: bool,
+    repo_name_pattern: Option<Regex>,
+    max_refs: Option<usize>,
+    user_namespaces: bool,
+    admin_users: Vec<String>,
+    self_serve: bool,
+    stale_pack_age: Duration,
+    maintenance: Maintenance,
+    motd: Option<Motd>,
+    banner_file: Option<PathBuf>,
+    buffer_size: usize,
 }
 
 impl Server {
@@ -23,92 +60,1046 @@ impl Server {
         host_key_path: PathBuf,
         authorized_keys_path: PathBuf,
         repos_dir: PathBuf,
+        index_cache: IndexCache,
     ) -> Self {
         Self {
             port,
             host_key_path,
             authorized_keys_path,
             repos_dir,
+            index_cache,
+            ref_events: RefEvents::new(),
+            transfer_stats: TransferStats::new(),
+            keepalive_interval: None,
+            keepalive_max: 3,
+            max_channels: 4,
+            bind_addr: net::DEFAULT_BIND_ADDR,
+            progress: false,
+            check_health: false,
+            partial_clone: false,
+            allow_import: false,
+            repo_name_pattern: None,
+            max_refs: None,
+            user_namespaces: false,
+            admin_users: Vec::new(),
+            self_serve: true,
+            stale_pack_age: DEFAULT_STALE_PACK_AGE,
+            maintenance: Maintenance::disabled(),
+            motd: None,
+            banner_file: None,
+            buffer_size: git_io::DEFAULT_BUFFER_SIZE,
         }
     }
 
+    /// Send agito's own status messages to the client's stderr during git
+    /// operations, so slow server-side preparation doesn't look like a hang
+    pub fn progress_messages(mut self, enabled: bool) -> Self {
+        self.progress = enabled;
+        self
+    }
+
+    /// Verify that a repository's `HEAD` resolves to a commit before serving
+    /// upload-pack, so corruption is reported instead of a broken transfer
+    pub fn check_repo_health(mut self, enabled: bool) -> Self {
+        self.check_health = enabled;
+        self
+    }
+
+    /// Let clients make partial clones (`git clone --filter=blob:none`) and
+    /// fetch the objects they left out later
+    pub fn partial_clone(mut self, enabled: bool) -> Self {
+        self.partial_clone = enabled;
+        self
+    }
+
+    /// Enable `agito-import`, which has the server fetch a repository from
+    /// a public HTTPS URL
+    pub fn allow_import(mut self, enabled: bool) -> Self {
+        self.allow_import = enabled;
+        self
+    }
+
+    /// Only create repositories whose names, without the `.git` suffix,
+    /// match `pattern`
+    pub fn repo_name_pattern(mut self, pattern: Regex) -> Self {
+        self.repo_name_pattern = Some(pattern);
+        self
+    }
+
+    /// Publish the refs each push changes to `events`, shared with the web
+    /// server's [`crate::web::WebServer::ref_events`]
+    pub fn ref_events(mut self, events: RefEvents) -> Self {
+        self.ref_events = events;
+        self
+    }
+
+    /// Add the bytes of every finished git transfer to `stats`, shared with
+    /// the web server's [`crate::web::WebServer::transfer_stats`]
+    pub fn transfer_stats(mut self, stats: TransferStats) -> Self {
+        self.transfer_stats = stats;
+        self
+    }
+
+    /// Reject pushes that would leave a repository with more than `limit`
+    /// refs, unless its `agito.maxRefs` config says otherwise
+    pub fn max_refs(mut self, limit: usize) -> Self {
+        self.max_refs = Some(limit);
+        self
+    }
+
+    /// Give each key's `agito-user` a `<user>/` directory that their created
+    /// repositories go into and bare repository names resolve against, and
+    /// that only they may change. Users in `admins` keep creating
+    /// repositories at the top level and may change any.
+    pub fn user_namespaces(mut self, enabled: bool, admins: Vec<String>) -> Self {
+        self.user_namespaces = enabled;
+        self.admin_users = admins;
+        self
+    }
+
+    /// Let any key with write access create and import repositories. When
+    /// disabled only keys with the `agito-admin` option may.
+    pub fn self_serve(mut self, enabled: bool) -> Self {
+        self.self_serve = enabled;
+        self
+    }
+
+    /// How long a temporary pack file must be untouched before
+    /// `agito-clean` treats it as left over from an interrupted push
+    pub fn stale_pack_age(mut self, age: Duration) -> Self {
+        self.stale_pack_age = age;
+        self
+    }
+
+    /// Refuse pushes while the server is in maintenance; fetches still work
+    pub fn maintenance(mut self, maintenance: Maintenance) -> Self {
+        self.maintenance = maintenance;
+        self
+    }
+
+    /// Send the contents of `path` to the client's stderr before every
+    /// command, re-reading it when it changes
+    pub fn motd_file(mut self, path: PathBuf) -> Self {
+        self.motd = Some(Motd::new(path));
+        self
+    }
+
+    /// Send the contents of `path` to clients before they authenticate, e.g.
+    /// a legal notice. Read once at startup.
+    pub fn banner_file(mut self, path: PathBuf) -> Self {
+        self.banner_file = Some(path);
+        self
+    }
+
+    /// Read git's output in chunks of up to `bytes`
+    pub fn io_buffer_size(mut self, bytes: usize) -> Self {
+        self.buffer_size = bytes.max(1);
+        self
+    }
+
+    /// Listen on a specific address instead of all interfaces
+    pub fn bind_addr(mut self, addr: IpAddr) -> Self {
+        self.bind_addr = addr;
+        self
+    }
+
+    /// Limit how many session channels a single connection may hold open
+    pub fn max_channels(mut self, max: usize) -> Self {
+        self.max_channels = max;
+        self
+    }
+
+    /// Send a keepalive request after `interval` of silence from the client,
+    /// dropping the connection after `max` unanswered requests. `None`
+    /// disables keepalives.
+    pub fn keepalive(mut self, interval: Option<Duration>, max: usize) -> Self {
+        self.keepalive_interval = interval;
+        self.keepalive_max = max;
+        self
+    }
+
     pub async fn start(self) -> Result<()> {
+        self.bind().await?.serve(std::future::pending::<()>()).await
+    }
+
+    /// Bind the SSH port and load the host key without accepting connections
+    /// yet, so the address is known up front, e.g. when binding port `0`
+    pub async fn bind(self) -> Result<BoundServer> {
+        // Start listening manually
+        let listener = net::bind(self.bind_addr, &self.port)?;
+        let acceptor = self.acceptor().await?;
+
+        tracing::info!("SSH server listening on {}", listener.local_addr()?);
+
+        Ok(BoundServer { listener, acceptor })
+    }
+
+    /// Load the host key and build an [`Acceptor`] for serving connections
+    /// accepted elsewhere, e.g. by a shared listener.
+    pub async fn acceptor(self) -> Result<Acceptor> {
         let host_key = self.get_host_key().await?;
 
+        // russh wants a `&'static str`; the banner lives as long as the
+        // server anyway, and this runs once per server
+        let auth_banner = match &self.banner_file {
+            Some(path) => Some(&*Box::leak(read_banner(path)?.into_boxed_str())),
+            None => None,
+        };
+
         let config = russh::server::Config {
-            inactivity_timeout: Some(std::time::Duration::from_secs(3600)),
-            auth_rejection_time: std::time::Duration::from_secs(3),
-            auth_rejection_time_initial: Some(std::time::Duration::from_secs(0)),
+            inactivity_timeout: Some(Duration::from_secs(3600)),
+            keepalive_interval: self.keepalive_interval,
+            keepalive_max: self.keepalive_max,
+            auth_rejection_time: Duration::from_secs(3),
+            auth_rejection_time_initial: Some(Duration::from_secs(0)),
             keys: vec![host_key],
+            auth_banner,
             ..Default::default()
         };
 
-        let config = Arc::new(config);
+        Ok(Acceptor {
+            config: Arc::new(config),
+            repos_dir: Arc::new(self.repos_dir),
+            authorized_keys: AuthorizedKeys::new(self.authorized_keys_path),
+            index_cache: self.index_cache,
+            ref_events: self.ref_events,
+            transfer_stats: self.transfer_stats,
+            max_channels: self.max_channels,
+            progress: self.progress,
+            check_health: self.check_health,
+            partial_clone: self.partial_clone,
+            allow_import: self.allow_import,
+            repo_name_pattern: self.repo_name_pattern,
+            max_refs: self.max_refs,
+            user_namespaces: self.user_namespaces,
+            admin_users: Arc::new(self.admin_users),
+            self_serve: self.self_serve,
+            stale_pack_age: self.stale_pack_age,
+            maintenance: self.maintenance,
+            motd: self.motd,
+            buffer_size: self.buffer_size,
+        })
+    }
+
+    async fn get_host_key(&self) -> Result<key::KeyPair> {
+        if let Some(key) = existing_host_key(&self.host_key_path)? {
+            return Ok(key);
+        }
 
-        let addr = format!("0.0.0.0:{}", self.port);
-        tracing::info!("SSH server listening on {}", addr);
+        // Generate new host key
+        tracing::info!("Generating new SSH host key at {:?}", self.host_key_path);
 
-        // Start listening manually
-        let listener = tokio::net::TcpListener::bind(&addr).await?;
-        
-        let repos_dir = Arc::new(self.repos_dir);
-        let authorized_keys_path = Arc::new(self.authorized_keys_path);
-        
-        loop {
-            let (stream, _addr) = listener.accept().await?;
-            let config = config.clone();
-            let repos_dir = repos_dir.clone();
-            let authorized_keys_path = authorized_keys_path.clone();
-            
-            tokio::spawn(async move {
-                let handler = SessionHandler {
-                    repos_dir: (*repos_dir).clone(),
-                    authorized_keys_path: (*authorized_keys_path).clone(),
-                };
-                let session = russh::server::run_stream(config, stream, handler).await;
-                if let Err(e) = session {
-                    tracing::error!("Session error: {}", e);
+        let result = Command::new("ssh-keygen")
+            .arg("-t")
+            .arg("rsa")
+            .arg("-b")
+            .arg("4096")
+            .arg("-f")
+            .arg(&self.host_key_path)
+            .arg("-N")
+            .arg("")
+            .status()
+            .await;
+
+        match result {
+            Ok(status) if status.success() => {
+                tracing::info!("Generated host key with ssh-keygen");
+            }
+            Ok(status) => {
+                tracing::warn!(
+                    "ssh-keygen failed ({}), generating ed25519 host key natively",
+                    status
+                );
+                self.generate_host_key()?;
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                tracing::info!("ssh-keygen not found, generating ed25519 host key natively");
+                self.generate_host_key()?;
+            }
+            Err(e) => {
+                tracing::warn!(
+                    "Failed to run ssh-keygen ({}), generating ed25519 host key natively",
+                    e
+                );
+                self.generate_host_key()?;
+            }
+        }
+
+        existing_host_key(&self.host_key_path)?.context("Host key was not generated")
+    }
+
+    /// Generate an ed25519 host key without ssh-keygen, writing the private
+    /// key as PKCS#8 PEM and the public key next to it like ssh-keygen does
+    fn generate_host_key(&self) -> Result<()> {
+        let key = key::KeyPair::generate_ed25519().context("Failed to generate host key")?;
+
+        let mut pem = Vec::new();
+        russh_keys::encode_pkcs8_pem(&key, &mut pem).context("Failed to encode host key")?;
+        // Created private, so the key is never readable by others even briefly
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let mut file = options
+            .open(&self.host_key_path)
+            .context("Failed to create host key")?;
+        io::Write::write_all(&mut file, &pem).context("Failed to write host key")?;
+
+        let mut public = Vec::new();
+        russh_keys::write_public_key_base64(&mut public, &key.clone_public_key()?)
+            .context("Failed to encode public host key")?;
+        let mut public_path = self.host_key_path.clone().into_os_string();
+        public_path.push(".pub");
+        fs::write(public_path, public).context("Failed to write public host key")?;
+
+        Ok(())
+    }
+}
+
+/// Default age after which leftover temporary pack files are removed
+pub const DEFAULT_STALE_PACK_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Environment variable holding a PEM host key, used instead of the key file
+const HOST_KEY_ENV: &str = "AGITO_HOST_KEY";
+
+/// The host key from [`HOST_KEY_ENV`] or the file at `path`, or `None` if
+/// there is neither and one has to be generated
+fn existing_host_key(path: &Path) -> Result<Option<key::KeyPair>> {
+    // A key injected through the environment never touches the disk
+    if let Ok(pem) = std::env::var(HOST_KEY_ENV) {
+        tracing::info!("Using SSH host key from {}", HOST_KEY_ENV);
+        return russh_keys::decode_secret_key(&pem, None)
+            .map(Some)
+            .with_context(|| format!("Failed to parse host key from {}", HOST_KEY_ENV));
+    }
+
+    if !path.exists() {
+        return Ok(None);
+    }
+
+    let key_data = fs::read(path).context("Failed to read host key")?;
+    let key = russh_keys::decode_secret_key(&String::from_utf8_lossy(&key_data), None)
+        .context("Failed to parse host key")?;
+    Ok(Some(key))
+}
+
+/// Pre-authentication banner text from `path`, ending in a newline so the
+/// client's next output starts on its own line
+pub fn read_banner(path: &Path) -> Result<String> {
+    let mut banner = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read SSH banner {:?}", path))?;
+    if !banner.ends_with('\n') {
+        banner.push('\n');
+    }
+    Ok(banner)
+}
+
+/// Fingerprint of the host key the server would use, without generating
+/// one; `None` means a key will be generated at `path` on startup
+pub fn check_host_key(path: &Path) -> Result<Option<String>> {
+    match existing_host_key(path)? {
+        Some(key) => Ok(Some(key.clone_public_key()?.fingerprint())),
+        None => Ok(None),
+    }
+}
+
+/// Number of keys in the authorized keys at `path` that the server accepts,
+/// and of entries it would skip as malformed
+pub fn check_authorized_keys(path: &Path) -> io::Result<(usize, usize)> {
+    let mut accepted = 0;
+    let mut skipped = 0;
+    for line in read_authorized_keys(path)?.lines() {
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match parse_authorized_key(line) {
+            Some(_) => accepted += 1,
+            None => skipped += 1,
+        }
+    }
+    Ok((accepted, skipped))
+}
+
+/// Extended data type for stderr (RFC 4254, section 5.2)
+const SSH_EXTENDED_DATA_STDERR: u32 = 1;
+
+/// agito commands that change repositories, which keys limited to
+/// `agito-perms=read` may not run and no key may run during maintenance
+const WRITE_COMMANDS: &[&str] = &[
+    "agito-create-repo",
+    "agito-import",
+    "agito-config",
+    "agito-set-head",
+    "agito-branch",
+    "agito-set-topics",
+    "agito-hide",
+    "agito-unhide",
+    "agito-clean",
+];
+
+/// The [`WRITE_COMMANDS`] entry `command` runs, if any
+fn write_command(command: &str) -> Option<&'static str> {
+    WRITE_COMMANDS.iter().copied().find(|name| command.starts_with(name))
+}
+
+/// Environment variables a client may pass to git services
+const FORWARDED_ENV: &[&str] = &["GIT_NAMESPACE"];
+
+/// Whether a client-supplied environment variable may be forwarded.
+///
+/// Namespaces are restricted to a conservative charset and may not contain
+/// `..` path components, since git maps them onto `refs/namespaces/<ns>/`.
+fn valid_env(name: &str, value: &str) -> bool {
+    if !FORWARDED_ENV.contains(&name) {
+        return false;
+    }
+
+    !value.is_empty()
+        && value.len() <= 128
+        && value
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | '/'))
+        && !value.split('/').any(|part| part.is_empty() || part == "..")
+}
+
+/// Whether an `agito-user` value can be used as a repository directory name
+fn valid_namespace(user: &str) -> bool {
+    !user.is_empty()
+        && user.len() <= 64
+        && !user.starts_with('.')
+        && user
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+/// Git services a key may run, from its `agito-perms` option
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Permission {
+    Read,
+    Write,
+    ReadWrite,
+}
+
+impl Permission {
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "read" => Some(Permission::Read),
+            "write" => Some(Permission::Write),
+            "rw" => Some(Permission::ReadWrite),
+            _ => None,
+        }
+    }
+
+    /// Whether `service` (`git-upload-pack` or `git-receive-pack`) is allowed
+    fn allows(self, service: &str) -> bool {
+        match service {
+            "git-upload-pack" => self != Permission::Write,
+            "git-receive-pack" => self != Permission::Read,
+            _ => false,
+        }
+    }
+}
+
+/// What an authorized key may do, from the options agito understands
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct KeyGrant {
+    permission: Permission,
+    /// `agito-admin`: may create repositories when self-serve is off
+    admin: bool,
+    /// `agito-user`: whose namespace the key acts in
+    user: Option<String>,
+}
+
+/// A key accepted by `authorized_keys`, with the options agito understands
+struct AuthorizedKey {
+    key: key::PublicKey,
+    permission: Permission,
+    admin: bool,
+    user: Option<String>,
+}
+
+/// Parse an `authorized_keys` line: `[options] [type] base64 [comment]`.
+///
+/// Lines holding only the base64 key are accepted too. Options are
+/// comma-separated and may quote values. A key with an option agito doesn't
+/// enforce, such as `from=` or `command=`, or with an unrecognized
+/// `agito-perms` value is skipped rather than given more access than the
+/// line intends.
+fn parse_authorized_key(line: &str) -> Option<AuthorizedKey> {
+    let fields = split_unquoted(line.trim(), |c| c.is_ascii_whitespace());
+    let key_index = fields
+        .iter()
+        .position(|field| russh_keys::parse_public_key_base64(field).is_ok())?;
+    let key = russh_keys::parse_public_key_base64(fields[key_index]).ok()?;
+
+    let options = match fields[..key_index] {
+        [options, _key_type] => Some(options),
+        [first] if !is_key_type(first) => Some(first),
+        _ => None,
+    };
+
+    let mut permission = Permission::ReadWrite;
+    let mut admin = false;
+    let mut user = None;
+    for option in options.map(|o| split_unquoted(o, |c| c == ',')).unwrap_or_default() {
+        if option == "agito-admin" {
+            admin = true;
+        } else if let Some(value) = option.strip_prefix("agito-perms=") {
+            let value = value.trim_matches('"');
+            permission = match Permission::parse(value) {
+                Some(permission) => permission,
+                None => {
+                    tracing::warn!("Skipping key with invalid agito-perms: {}", value);
+                    return None;
                 }
+            };
+        } else if let Some(value) = option.strip_prefix("agito-user=") {
+            let value = value.trim_matches('"');
+            if !valid_namespace(value) {
+                tracing::warn!("Skipping key with invalid agito-user: {}", value);
+                return None;
+            }
+            user = Some(value.to_string());
+        } else {
+            tracing::warn!("Skipping key with option agito does not enforce: {}", option);
+            return None;
+        }
+    }
+
+    Some(AuthorizedKey {
+        key,
+        permission,
+        admin,
+        user,
+    })
+}
+
+/// Grant for each authorized key, by its wire encoding. The first entry for
+/// a key wins, as it did when the file was scanned line by line.
+type KeyIndex = Arc<HashMap<Vec<u8>, KeyGrant>>;
+
+/// Modification time and size of each file the keys were read from
+type KeyFilesStamp = Vec<(PathBuf, Option<SystemTime>, u64)>;
+
+/// Authorized keys parsed once into a [`KeyIndex`], so checking a login is a
+/// single lookup however long the file is, and doesn't take longer the
+/// further down the file the key appears. Re-parsed when a key file changes,
+/// so edits still apply to the next login.
+#[derive(Clone)]
+struct AuthorizedKeys {
+    path: Arc<PathBuf>,
+    cached: Arc<Mutex<Option<(KeyFilesStamp, KeyIndex)>>>,
+}
+
+impl AuthorizedKeys {
+    fn new(path: PathBuf) -> Self {
+        Self {
+            path: Arc::new(path),
+            cached: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// What `key` may do, or `None` if it is not authorized
+    fn lookup(&self, key: &key::PublicKey) -> io::Result<Option<KeyGrant>> {
+        let stamp = key_files_stamp(&self.path)?;
+
+        let mut cached = self.cached.lock().unwrap();
+        let index = match &*cached {
+            Some((at, index)) if *at == stamp => index.clone(),
+            _ => {
+                let contents = read_authorized_keys(&self.path)?;
+                let index: KeyIndex = Arc::new(index_authorized_keys(&contents));
+                *cached = Some((stamp, index.clone()));
+                index
+            }
+        };
+
+        Ok(index.get(&key.public_key_bytes()).cloned())
+    }
+}
+
+fn index_authorized_keys(contents: &str) -> HashMap<Vec<u8>, KeyGrant> {
+    let mut index = HashMap::new();
+    for line in contents.lines() {
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if let Some(entry) = parse_authorized_key(line) {
+            index.entry(entry.key.public_key_bytes()).or_insert(KeyGrant {
+                permission: entry.permission,
+                admin: entry.admin,
+                user: entry.user,
             });
         }
     }
+    index
+}
 
-    async fn get_host_key(&self) -> Result<key::KeyPair> {
-        // Check if host key exists
-        if !self.host_key_path.exists() {
-            // Generate new host key
-            tracing::info!("Generating new SSH host key at {:?}", self.host_key_path);
-
-            let status = Command::new("ssh-keygen")
-                .arg("-t")
-                .arg("rsa")
-                .arg("-b")
-                .arg("4096")
-                .arg("-f")
-                .arg(&self.host_key_path)
-                .arg("-N")
-                .arg("")
-                .status()
-                .await
-                .context("Failed to generate host key")?;
+fn key_files_stamp(path: &Path) -> io::Result<KeyFilesStamp> {
+    Ok(authorized_key_files(path)?
+        .into_iter()
+        .map(|file| match fs::metadata(&file) {
+            Ok(meta) => {
+                let modified = meta.modified().ok();
+                (file, modified, meta.len())
+            }
+            Err(_) => (file, None, 0),
+        })
+        .collect())
+}
+
+/// Contents of an `authorized_keys` file, or of every file in a directory of
+/// them (like `authorized_keys.d/`) in name order.
+///
+/// Hidden files and editor backups (`~` suffix) in a directory are skipped,
+/// as are files that can't be read, so one bad file doesn't lock everyone out.
+fn read_authorized_keys(path: &Path) -> io::Result<String> {
+    if !path.is_dir() {
+        return fs::read_to_string(path);
+    }
+
+    let mut keys = String::new();
+    for file in authorized_key_files(path)? {
+        match fs::read_to_string(&file) {
+            Ok(contents) => {
+                keys.push_str(&contents);
+                keys.push('\n');
+            }
+            Err(e) => tracing::warn!("Skipping unreadable key file {:?}: {}", file, e),
+        }
+    }
+    Ok(keys)
+}
+
+/// The files [`read_authorized_keys`] reads for `path`
+fn authorized_key_files(path: &Path) -> io::Result<Vec<PathBuf>> {
+    if !path.is_dir() {
+        return Ok(vec![path.to_path_buf()]);
+    }
+
+    let mut files: Vec<PathBuf> = fs::read_dir(path)?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| {
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            !name.starts_with('.') && !name.ends_with('~')
+        })
+        .map(|entry| entry.path())
+        .filter(|path| path.is_file())
+        .collect();
+    files.sort();
+    Ok(files)
+}
+
+fn is_key_type(field: &str) -> bool {
+    ["ssh-", "ecdsa-", "sk-"]
+        .iter()
+        .any(|prefix| field.starts_with(prefix))
+}
+
+/// Split on `separator` characters that are not inside double quotes,
+/// dropping empty pieces
+fn split_unquoted(text: &str, separator: impl Fn(char) -> bool) -> Vec<&str> {
+    let mut pieces = Vec::new();
+    let mut start = 0;
+    let mut quoted = false;
+    for (i, c) in text.char_indices() {
+        if c == '"' {
+            quoted = !quoted;
+        } else if !quoted && separator(c) {
+            pieces.push(&text[start..i]);
+            start = i + c.len_utf8();
+        }
+    }
+    pieces.push(&text[start..]);
+    pieces.retain(|piece| !piece.is_empty());
+    pieces
+}
+
+/// Split an exec command into words the way a POSIX shell would, so
+/// `git-upload-pack '/my repo.git'` names one path.
+///
+/// Single quotes keep their contents literally; inside double quotes and
+/// unquoted text a backslash escapes the next character. Returns `None` if a
+/// quote or escape is left unterminated.
+fn split_shell_words(command: &str) -> Option<Vec<String>> {
+    let mut words = Vec::new();
+    let mut word: Option<String> = None;
+    let mut chars = command.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_ascii_whitespace() => {
+                words.extend(word.take());
+            }
+            '\'' => {
+                let word = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next()? {
+                        '\'' => break,
+                        c => word.push(c),
+                    }
+                }
+            }
+            '"' => {
+                let word = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next()? {
+                        '"' => break,
+                        '\\' => word.push(chars.next()?),
+                        c => word.push(c),
+                    }
+                }
+            }
+            '\\' => word.get_or_insert_with(String::new).push(chars.next()?),
+            c => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    words.extend(word);
+
+    Some(words)
+}
+
+/// Resolve the repository path a git command names to a directory under
+/// `repos_dir`, without touching the filesystem.
+///
+/// Leading slashes are dropped, since `ssh://host/name.git` URLs arrive as
+/// `/name.git`. What remains must be a relative path of plain components
+/// (see [`crate::git::valid_repo_path`]), so it can't escape `repos_dir`.
+fn resolve_repo_path(repos_dir: &Path, raw: &str) -> crate::error::Result<PathBuf> {
+    let name = raw.trim_start_matches('/');
+    if name.is_empty() {
+        return Err(AgitoError::InvalidName("Missing repository path".to_string()));
+    }
+    if !crate::git::valid_repo_path(name) {
+        return Err(AgitoError::InvalidName(format!(
+            "Invalid repository path: {}",
+            sanitize_command(raw)
+        )));
+    }
+
+    Ok(repos_dir.join(name))
+}
+
+/// Config passed to `git upload-pack` when partial clones are enabled
+const PARTIAL_CLONE_CONFIG: &[&str] = &[
+    "-c",
+    "uploadpack.allowFilter=true",
+    "-c",
+    "uploadpack.allowReachableSHA1InWant=true",
+];
+
+/// Whether a client may pass `flag` to `service`.
+///
+/// Only options that change what the service reports are allowed; anything
+/// that could weaken its checks (like `--skip-connectivity-check`) is not.
+fn allowed_flag(service: &str, flag: &str) -> bool {
+    match service {
+        "git-upload-pack" => {
+            matches!(flag, "--strict" | "--no-strict" | "--advertise-refs")
+                || flag.strip_prefix("--timeout=").is_some_and(|secs| {
+                    !secs.is_empty() && secs.len() <= 6 && secs.bytes().all(|b| b.is_ascii_digit())
+                })
+        }
+        "git-receive-pack" => flag == "--advertise-refs",
+        _ => false,
+    }
+}
+
+/// Split a git service's arguments into the flags to forward and the
+/// repository path, refusing unknown flags rather than dropping them
+fn parse_service_args<'a>(
+    service: &str,
+    args: &[&'a str],
+) -> crate::error::Result<(Vec<&'a str>, &'a str)> {
+    let (flags, paths): (Vec<&str>, Vec<&str>) = args.iter().partition(|arg| arg.starts_with('-'));
+
+    if let Some(flag) = flags.iter().find(|flag| !allowed_flag(service, flag)) {
+        return Err(AgitoError::InvalidName(format!(
+            "Unsupported option for {}: {}",
+            service,
+            sanitize_command(flag)
+        )));
+    }
+
+    match paths[..] {
+        [path] => Ok((flags, path)),
+        _ => Err(AgitoError::InvalidName(format!(
+            "Expected one repository path for {}",
+            service
+        ))),
+    }
+}
 
-            if !status.success() {
-                anyhow::bail!("Failed to generate host key");
+/// The repository name and whether `--dry-run` was given, from the
+/// arguments of `agito-create-repo`, or `None` unless there is exactly one
+/// name and no other option
+fn parse_create_args<'a>(args: &[&'a str]) -> Option<(&'a str, bool)> {
+    match args[..] {
+        [name] if !name.starts_with('-') => Some((name, false)),
+        ["--dry-run", name] | [name, "--dry-run"] if !name.starts_with('-') => Some((name, true)),
+        _ => None,
+    }
+}
+
+/// Longest command prefix echoed back to the client in error messages
+const MAX_ECHOED_COMMAND_LEN: usize = 256;
+
+/// Make a client-supplied command safe to echo back to a terminal.
+///
+/// Control characters (including the ESC that starts ANSI sequences) are
+/// dropped and the result is truncated to `MAX_ECHOED_COMMAND_LEN` characters.
+fn sanitize_command(command: &str) -> String {
+    let mut sanitized: String = command
+        .chars()
+        .filter(|c| !c.is_control())
+        .take(MAX_ECHOED_COMMAND_LEN)
+        .collect();
+
+    if command.chars().filter(|c| !c.is_control()).count() > MAX_ECHOED_COMMAND_LEN {
+        sanitized.push_str("...");
+    }
+
+    sanitized
+}
+
+/// Process output queued for a channel: the extended data type (`None` for
+/// stdout) and the bytes
+type Output = (Option<u32>, Vec<u8>);
+
+/// Write queued output to `channel` without exceeding the client's window.
+///
+/// russh buffers data that doesn't fit the window but sends EOF and close
+/// immediately, which would cut off the end of a large pack; waiting for the
+/// window keeps that buffer empty. Messages the client sends meanwhile are
+/// already handled by the session handler and are discarded here.
+async fn send_output(channel: &mut Channel<Msg>, mut output: mpsc::Receiver<Output>) {
+    let mut stdout = channel.make_writer_ext(None);
+    let mut stderr = channel.make_writer_ext(Some(SSH_EXTENDED_DATA_STDERR));
+
+    loop {
+        let (ext, data) = tokio::select! {
+            item = output.recv() => match item {
+                Some(item) => item,
+                None => return,
+            },
+            msg = channel.wait() => match msg {
+                Some(_) => continue,
+                None => return,
+            },
+        };
+        let writer = if ext.is_some() { &mut stderr } else { &mut stdout };
+
+        let mut rest = &data[..];
+        while !rest.is_empty() {
+            let writable = channel.writable_packet_size().await.min(rest.len());
+            if writable == 0 {
+                // Wait for a window adjustment
+                if channel.wait().await.is_none() {
+                    return;
+                }
+                continue;
+            }
+            match writer.write(&rest[..writable]).await {
+                Ok(n) => rest = &rest[n..],
+                Err(_) => return,
+            }
+        }
+    }
+}
+
+/// Message of the day shown to clients, cached until the file's
+/// modification time changes
+#[derive(Clone)]
+struct Motd {
+    path: Arc<PathBuf>,
+    cached: Arc<Mutex<Option<(SystemTime, MotdText)>>>,
+}
+
+type MotdText = Arc<[u8]>;
+
+impl Motd {
+    fn new(path: PathBuf) -> Self {
+        Self {
+            path: Arc::new(path),
+            cached: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// The current message, or `None` if the file is missing or empty
+    fn contents(&self) -> Option<MotdText> {
+        let modified = match fs::metadata(&*self.path).and_then(|meta| meta.modified()) {
+            Ok(modified) => modified,
+            Err(e) => {
+                if e.kind() != io::ErrorKind::NotFound {
+                    tracing::warn!("Failed to read MOTD file {:?}: {}", self.path, e);
+                }
+                return None;
+            }
+        };
+
+        let mut cached = self.cached.lock().unwrap();
+        let contents = match &*cached {
+            Some((at, contents)) if *at == modified => contents.clone(),
+            _ => {
+                let mut contents = match fs::read(&*self.path) {
+                    Ok(contents) => contents,
+                    Err(e) => {
+                        tracing::warn!("Failed to read MOTD file {:?}: {}", self.path, e);
+                        return None;
+                    }
+                };
+                if !contents.is_empty() && !contents.ends_with(b"\n") {
+                    contents.push(b'\n');
+                }
+                let contents: MotdText = contents.into();
+                *cached = Some((modified, contents.clone()));
+                contents
+            }
+        };
+
+        (!contents.is_empty()).then_some(contents)
+    }
+}
+
+/// An SSH server bound to its port, from [`Server::bind`]
+pub struct BoundServer {
+    listener: TcpListener,
+    acceptor: Acceptor,
+}
+
+impl BoundServer {
+    /// Address the server is listening on
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    /// Accept connections until `shutdown` completes. Sessions already in
+    /// progress are left to finish.
+    pub async fn serve(self, shutdown: impl Future) -> Result<()> {
+        tokio::pin!(shutdown);
+        loop {
+            tokio::select! {
+                _ = &mut shutdown => return Ok(()),
+                accepted = self.listener.accept() => {
+                    let (stream, _addr) = accepted?;
+                    self.acceptor.accept(stream);
+                }
             }
         }
+    }
+}
 
-        // Load host key
-        let key_data = fs::read(&self.host_key_path).context("Failed to read host key")?;
-        let key = russh_keys::decode_secret_key(&String::from_utf8_lossy(&key_data), None)
-            .context("Failed to parse host key")?;
+/// Serves SSH sessions on already-accepted TCP streams
+#[derive(Clone)]
+pub struct Acceptor {
+    config: Arc<russh::server::Config>,
+    repos_dir: Arc<PathBuf>,
+    authorized_keys: AuthorizedKeys,
+    index_cache: IndexCache,
+    ref_events: RefEvents,
+    transfer_stats: TransferStats,
+    max_channels: usize,
+    progress: bool,
+    check_health: bool,
+    partial_clone: bool,
+    allow_import: bool,
+    repo_name_pattern: Option<Regex>,
+    max_refs: Option<usize>,
+    user_namespaces: bool,
+    admin_users: Arc<Vec<String>>,
+    self_serve: bool,
+    stale_pack_age: Duration,
+    maintenance: Maintenance,
+    motd: Option<Motd>,
+    buffer_size: usize,
+}
+
+impl Acceptor {
+    /// Run an SSH session on `stream` in a background task
+    pub fn accept(&self, stream: TcpStream) {
+        let config = self.config.clone();
+        let handler = SessionHandler {
+            repos_dir: (*self.repos_dir).clone(),
+            authorized_keys: self.authorized_keys.clone(),
+            index_cache: self.index_cache.clone(),
+            ref_events: self.ref_events.clone(),
+            transfer_stats: self.transfer_stats.clone(),
+            max_channels: self.max_channels,
+            open_channels: HashSet::new(),
+            channel_handles: HashMap::new(),
+            user: None,
+            channel_env: HashMap::new(),
+            channel_stdin: HashMap::new(),
+            permission: Permission::ReadWrite,
+            admin: false,
+            progress: self.progress,
+            check_health: self.check_health,
+            partial_clone: self.partial_clone,
+            allow_import: self.allow_import,
+            repo_name_pattern: self.repo_name_pattern.clone(),
+            max_refs: self.max_refs,
+            user_namespaces: self.user_namespaces,
+            admin_users: self.admin_users.clone(),
+            self_serve: self.self_serve,
+            stale_pack_age: self.stale_pack_age,
+            maintenance: self.maintenance.clone(),
+            motd: self.motd.clone(),
+            buffer_size: self.buffer_size,
+        };
+
+        let span = match stream.peer_addr() {
+            Ok(peer) => tracing::info_span!("session", peer = %peer),
+            Err(_) => tracing::info_span!("session", peer = "unknown"),
+        };
 
-        Ok(key)
+        tokio::spawn(
+            async move {
+                let session = russh::server::run_stream(config, stream, handler).await;
+                if let Err(e) = session {
+                    tracing::error!("Session error: {}", e);
+                }
+            }
+            .instrument(span),
+        );
     }
 }
 
 struct SessionHandler {
     repos_dir: PathBuf,
-    authorized_keys_path: PathBuf,
+    authorized_keys: AuthorizedKeys,
+    index_cache: IndexCache,
+    ref_events: RefEvents,
+    transfer_stats: TransferStats,
+    max_channels: usize,
+    open_channels: HashSet<ChannelId>,
+    /// Channels waiting for a command; git commands take theirs to write
+    /// output with flow control
+    channel_handles: HashMap<ChannelId, Channel<Msg>>,
+    /// The key's `agito-user`, never the SSH user name, which the client
+    /// picks freely
+    user: Option<String>,
+    channel_env: HashMap<ChannelId, Vec<(String, String)>>,
+    channel_stdin: HashMap<ChannelId, mpsc::Sender<Vec<u8>>>,
+    permission: Permission,
+    /// The key has the `agito-admin` option
+    admin: bool,
+    progress: bool,
+    check_health: bool,
+    partial_clone: bool,
+    allow_import: bool,
+    repo_name_pattern: Option<Regex>,
+    max_refs: Option<usize>,
+    user_namespaces: bool,
+    admin_users: Arc<Vec<String>>,
+    self_serve: bool,
+    stale_pack_age: Duration,
+    maintenance: Maintenance,
+    motd: Option<Motd>,
+    buffer_size: usize,
 }
 
 #[async_trait]
@@ -123,192 +1114,1042 @@ impl russh::server::Handler for SessionHandler {
         tracing::info!("Public key auth attempt for user: {}", user);
 
         // Read authorized keys
-        if !self.authorized_keys_path.exists() {
+        if !self.authorized_keys.path.exists() {
             return Ok(Auth::Reject {
                 proceed_with_methods: None,
             });
         }
 
-        let auth_keys = fs::read_to_string(&self.authorized_keys_path)?;
-
-        for line in auth_keys.lines() {
-            if line.trim().is_empty() || line.starts_with('#') {
-                continue;
-            }
+        if let Some(grant) = self.authorized_keys.lookup(public_key)? {
+            tracing::info!(
+                "User {} authenticated successfully as {:?}",
+                user,
+                grant.user
+            );
+            self.user = grant.user;
+            self.permission = grant.permission;
+            self.admin = grant.admin;
+            return Ok(Auth::Accept);
+        }
+
+        Ok(Auth::Reject {
+            proceed_with_methods: None,
+        })
+    }
+
+    async fn channel_open_session(
+        &mut self,
+        channel: Channel<Msg>,
+        _session: &mut Session,
+    ) -> Result<bool, Self::Error> {
+        if self.open_channels.len() >= self.max_channels {
+            tracing::warn!(
+                "Rejecting channel: session already has {} open channels",
+                self.open_channels.len()
+            );
+            return Ok(false);
+        }
+
+        self.open_channels.insert(channel.id());
+        self.channel_handles.insert(channel.id(), channel);
+        Ok(true)
+    }
+
+    async fn data(
+        &mut self,
+        channel: ChannelId,
+        data: &[u8],
+        _session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        if let Some(stdin) = self.channel_stdin.get(&channel) {
+            if stdin.send(data.to_vec()).await.is_err() {
+                // The process has exited; drop further input
+                self.channel_stdin.remove(&channel);
+            }
+        }
+        Ok(())
+    }
+
+    async fn channel_eof(
+        &mut self,
+        channel: ChannelId,
+        _session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        // The channel's command may still be running, so it keeps its slot
+        // until the channel is closed
+        self.channel_stdin.remove(&channel);
+        Ok(())
+    }
+
+    async fn channel_close(
+        &mut self,
+        channel: ChannelId,
+        _session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        self.open_channels.remove(&channel);
+        self.channel_handles.remove(&channel);
+        self.channel_env.remove(&channel);
+        self.channel_stdin.remove(&channel);
+        Ok(())
+    }
+
+    async fn env_request(
+        &mut self,
+        channel: ChannelId,
+        variable_name: &str,
+        variable_value: &str,
+        _session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        if !valid_env(variable_name, variable_value) {
+            tracing::debug!("Ignoring environment variable: {}", variable_name);
+            return Ok(());
+        }
+
+        self.channel_env
+            .entry(channel)
+            .or_default()
+            .push((variable_name.to_string(), variable_value.to_string()));
+        Ok(())
+    }
+
+    async fn exec_request(
+        &mut self,
+        channel: ChannelId,
+        data: &[u8],
+        session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        let command = String::from_utf8_lossy(data);
+        tracing::info!("Executing command: {}", command);
+
+        if let Some(motd) = self.motd.as_ref().and_then(Motd::contents) {
+            session.extended_data(channel, SSH_EXTENDED_DATA_STDERR, motd.to_vec().into());
+        }
+
+        if let Some(name) = write_command(&command) {
+            if self.refuse_read_only(channel, name, session)
+                || self.refuse_in_maintenance(channel, name, session)
+            {
+                return Ok(());
+            }
+        }
+
+        if command.starts_with("git-upload-pack") || command.starts_with("git-receive-pack") {
+            self.handle_git_command(channel, &command, session).await?;
+        } else if command.starts_with("agito-create-repo") {
+            self.handle_create_repo(channel, &command, session).await?;
+        } else if command.starts_with("agito-import") {
+            self.handle_import(channel, &command, session).await?;
+        } else if command.starts_with("agito-config") {
+            self.handle_config(channel, &command, session).await?;
+        } else if command.starts_with("agito-set-head") {
+            self.handle_set_head(channel, &command, session).await?;
+        } else if command.starts_with("agito-branch") {
+            self.handle_branch(channel, &command, session).await?;
+        } else if command.starts_with("agito-set-topics") {
+            self.handle_set_topics(channel, &command, session).await?;
+        } else if command.starts_with("agito-hide") {
+            self.handle_set_hidden(channel, &command, true, session).await?;
+        } else if command.starts_with("agito-unhide") {
+            self.handle_set_hidden(channel, &command, false, session).await?;
+        } else if command.starts_with("agito-clean") {
+            self.handle_clean(channel, &command, session).await?;
+        } else {
+            self.handle_unknown_command(channel, &command, session);
+        }
+
+        Ok(())
+    }
+}
+
+impl SessionHandler {
+    /// Refuse the [`WRITE_COMMANDS`] entry `name` unless the key may push.
+    /// Returns whether the command was refused.
+    fn refuse_read_only(&self, channel: ChannelId, name: &str, session: &mut Session) -> bool {
+        if self.permission.allows("git-receive-pack") {
+            return false;
+        }
+        tracing::info!("Refusing {}: key is limited to {:?}", name, self.permission);
+        let e = AgitoError::AccessDenied(format!("Permission denied: this key may not run {}", name));
+        self.send_error(channel, &e, session);
+        true
+    }
+
+    /// Refuse to create a repository unless self-serve creation is on or the
+    /// key is an admin's. Returns whether the command was refused.
+    fn refuse_creation(&self, channel: ChannelId, session: &mut Session) -> bool {
+        if self.self_serve || self.admin {
+            return false;
+        }
+        let e = AgitoError::AccessDenied(
+            "Repository creation is disabled; contact an administrator".to_string(),
+        );
+        self.send_error(channel, &e, session);
+        true
+    }
+
+    /// Whether the key's user was passed with `--admin-user`
+    fn is_admin_user(&self) -> bool {
+        let user = self.user.as_deref();
+        self.admin_users.iter().any(|admin| Some(admin.as_str()) == user)
+    }
+
+    /// The directory the key's user's repositories live in, if user
+    /// namespaces are enabled and the user is not an admin
+    fn namespace(&self) -> Option<&str> {
+        if !self.user_namespaces || self.is_admin_user() {
+            return None;
+        }
+        self.user.as_deref()
+    }
+
+    /// Refuse changes to a repository in another user's namespace. Shared
+    /// top-level repositories, `agito-admin` keys and admin users are exempt.
+    fn check_owner(&self, repo_name: &str) -> crate::error::Result<()> {
+        let Some((owner, _)) = repo_name.split_once('/') else {
+            return Ok(());
+        };
+        if !self.user_namespaces || self.admin || self.is_admin_user() {
+            return Ok(());
+        }
+        if self.user.as_deref() == Some(owner) {
+            return Ok(());
+        }
+        Err(AgitoError::AccessDenied(format!(
+            "Permission denied: {} belongs to {}",
+            repo_name, owner
+        )))
+    }
+
+    /// Map a repository argument to its name and path under `repos_dir`,
+    /// adding the `.git` suffix if missing
+    fn resolve_repo(&self, name: &str) -> crate::error::Result<(String, PathBuf)> {
+        let mut repo_name = name.to_string();
+        if !repo_name.ends_with(".git") {
+            repo_name.push_str(".git");
+        }
+
+        // Nested names like `team/tools.git` are fine, escaping repos_dir isn't
+        if !crate::git::valid_repo_path(&repo_name) {
+            return Err(AgitoError::InvalidName("Invalid repository name".to_string()));
+        }
+
+        let repo_path = self.repos_dir.join(&repo_name);
+        if !repo_path.exists() {
+            return Err(AgitoError::RepoNotFound(repo_name));
+        }
+        self.check_owner(&repo_name)?;
+
+        Ok((repo_name, repo_path))
+    }
+
+    /// Name under `repos_dir` for a repository the user is creating as
+    /// `name`: with the `.git` suffix, inside their namespace if they have one
+    fn new_repo_name(&self, name: &str) -> Result<String, String> {
+        let mut repo_name = name.to_string();
+
+        // Ensure repo name ends with .git
+        if !repo_name.ends_with(".git") {
+            repo_name.push_str(".git");
+        }
+
+        // Validate repo name
+        if repo_name.contains("..") || repo_name.contains('/') {
+            return Err("Invalid repository name".to_string());
+        }
+
+        if let Some(pattern) = &self.repo_name_pattern {
+            let base = repo_name.trim_end_matches(".git");
+            if !pattern.is_match(base) {
+                return Err(format!(
+                    "Repository name {:?} does not match this server's naming policy: {}",
+                    base, pattern
+                ));
+            }
+        }
+
+        if self.user_namespaces && !self.is_admin_user() {
+            let Some(user) = self.namespace() else {
+                return Err(
+                    "This key has no agito-user option, so it has no namespace to create \
+                     repositories in"
+                        .to_string(),
+                );
+            };
+            repo_name = format!("{}/{}", user, repo_name);
+        }
+
+        Ok(repo_name)
+    }
+
+    /// [`Self::resolve_repo`], reporting the error to the client if `name`
+    /// doesn't resolve
+    fn resolve_or_report(
+        &self,
+        channel: ChannelId,
+        name: &str,
+        session: &mut Session,
+    ) -> Option<(String, PathBuf)> {
+        match self.resolve_repo(name) {
+            Ok(repo) => Some(repo),
+            Err(e) => {
+                self.send_error(channel, &e, session);
+                None
+            }
+        }
+    }
+
+    /// Refuse a command that changes repositories while the server is in
+    /// maintenance, showing the maintenance message. Returns whether the
+    /// command was refused.
+    fn refuse_in_maintenance(&self, channel: ChannelId, what: &str, session: &mut Session) -> bool {
+        if !self.maintenance.is_enabled() {
+            return false;
+        }
+        tracing::info!("Refusing {} during maintenance", what);
+        let msg = format!("{}\n", self.maintenance.message());
+        session.data(channel, msg.into_bytes().into());
+        session.exit_status_request(channel, 1);
+        session.eof(channel);
+        session.close(channel);
+        true
+    }
+
+    /// Show how a command is used, exiting with status 1
+    fn send_usage(&self, channel: ChannelId, usage: &str, session: &mut Session) {
+        let msg = format!("Usage: {}\n", usage);
+        session.data(channel, msg.into_bytes().into());
+        session.exit_status_request(channel, 1);
+        session.eof(channel);
+        session.close(channel);
+    }
+
+    /// Report a failed command, exiting with the code for its kind of error
+    fn send_error(&self, channel: ChannelId, error: &AgitoError, session: &mut Session) {
+        let msg = format!("{}\n", error);
+        session.data(channel, msg.into_bytes().into());
+        session.exit_status_request(channel, error.exit_code());
+        session.eof(channel);
+        session.close(channel);
+    }
+
+    fn handle_unknown_command(&mut self, channel: ChannelId, command: &str, session: &mut Session) {
+        let msg = format!("Unknown command: {}\n", sanitize_command(command));
+        session.data(channel, msg.into_bytes().into());
+        session.exit_status_request(channel, 1);
+        session.eof(channel);
+        session.close(channel);
+    }
+
+    async fn handle_git_command(
+        &mut self,
+        channel: ChannelId,
+        command: &str,
+        session: &mut Session,
+    ) -> Result<()> {
+        let Some(words) = split_shell_words(command) else {
+            let e = AgitoError::InvalidName("Unterminated quote in command".to_string());
+            self.send_error(channel, &e, session);
+            return Ok(());
+        };
+        let parts: Vec<&str> = words.iter().map(String::as_str).collect();
+        if parts.len() < 2 {
+            session.data(channel, b"Invalid git command\n".to_vec().into());
+            session.exit_status_request(channel, 1);
+            session.eof(channel);
+            session.close(channel);
+            return Ok(());
+        }
+
+        let git_cmd = parts[0];
+        let (flags, repo_path) = match parse_service_args(git_cmd, &parts[1..]) {
+            Ok(args) => args,
+            Err(e) => {
+                self.send_error(channel, &e, session);
+                return Ok(());
+            }
+        };
+
+        let push = git_cmd == "git-receive-pack";
+        if push && self.refuse_in_maintenance(channel, "pushes", session) {
+            return Ok(());
+        }
+
+        if !self.permission.allows(git_cmd) {
+            tracing::info!("Refusing {}: key is limited to {:?}", git_cmd, self.permission);
+            let e = AgitoError::AccessDenied(format!(
+                "Permission denied: this key may not run {}",
+                git_cmd
+            ));
+            self.send_error(channel, &e, session);
+            return Ok(());
+        }
+
+        let full_path = match resolve_repo_path(&self.repos_dir, repo_path) {
+            Ok(path) => path,
+            Err(e) => {
+                self.send_error(channel, &e, session);
+                return Ok(());
+            }
+        };
+
+        // Bare names refer to the user's own repository when one exists;
+        // `owner/name` paths always address the repository directly
+        let bare_name = full_path.parent() == Some(self.repos_dir.as_path());
+        let namespaced = match self.namespace() {
+            Some(user) if bare_name => {
+                full_path.file_name().map(|name| self.repos_dir.join(user).join(name))
+            }
+            _ => None,
+        };
+        let full_path = match namespaced {
+            Some(path) if path.exists() => path,
+            _ => full_path,
+        };
+        // Relative to repos_dir, for messages
+        let repo_path = full_path
+            .strip_prefix(&self.repos_dir)
+            .unwrap_or(&full_path)
+            .to_string_lossy()
+            .into_owned();
+        let repo_path = repo_path.as_str();
+
+        // Check if repository exists
+        if !full_path.exists() {
+            let e = AgitoError::RepoNotFound(repo_path.to_string());
+            self.send_error(channel, &e, session);
+            return Ok(());
+        }
+
+        if !crate::git::is_bare_repo(&full_path) {
+            tracing::warn!("Refusing to serve non-bare repository {:?}", full_path);
+            let msg = format!("Not a bare repository: {}\n", repo_path);
+            session.data(channel, msg.into_bytes().into());
+            session.exit_status_request(channel, 1);
+            session.eof(channel);
+            session.close(channel);
+            return Ok(());
+        }
+
+        if git_cmd == "git-receive-pack" {
+            if let Err(e) = self.check_owner(repo_path) {
+                self.send_error(channel, &e, session);
+                return Ok(());
+            }
+        }
+
+        if git_cmd == "git-receive-pack" && crate::git::is_archived(&full_path) {
+            tracing::info!("Refusing push to archived repository {:?}", full_path);
+            let e = AgitoError::AccessDenied(format!(
+                "Repository {} is archived and read-only",
+                repo_path
+            ));
+            self.send_error(channel, &e, session);
+            return Ok(());
+        }
+
+        if self.check_health
+            && git_cmd == "git-upload-pack"
+            && !crate::git::is_healthy(&full_path)
+        {
+            tracing::warn!("Repository failed health check: {:?}", full_path);
+            let msg = format!("Repository is unavailable: {}\n", repo_path);
+            session.data(channel, msg.into_bytes().into());
+            session.exit_status_request(channel, 1);
+            session.eof(channel);
+            session.close(channel);
+            return Ok(());
+        }
+
+        let mut output_channel = self
+            .channel_handles
+            .remove(&channel)
+            .context("Git command on a channel that is not open")?;
+
+        // Execute git command
+        let env = self.channel_env.get(&channel).cloned().unwrap_or_default();
+        // `git-upload-pack` runs as `git upload-pack` so the configured binary is used
+        let service = git_cmd.trim_start_matches("git-");
+        let mut command = Command::from(crate::git::git_command());
+        if self.partial_clone && service == "upload-pack" && crate::git::supports_partial_clone() {
+            // Lazy fetches of omitted blobs ask for them by id, which the
+            // protocol v0 clients we serve may only do with
+            // allowReachableSHA1InWant
+            command.args(PARTIAL_CLONE_CONFIG);
+        }
+        if let Some(limit) = self.max_refs.filter(|_| service == "receive-pack") {
+            // Read by `agito-hook pre-receive` when the repository sets no limit
+            command.env(crate::hooks::MAX_REFS_ENV, limit.to_string());
+        }
+        // Snapshot refs before a push so the changes can be published after
+        let refs_before = (service == "receive-pack" && self.ref_events.has_subscribers())
+            .then(|| crate::git::ref_targets(&full_path));
+        let mut child = command
+            .arg(service)
+            .args(&flags)
+            .arg(&full_path)
+            .envs(env)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+
+        let mut stdin = child.stdin.take().unwrap();
+        let mut stdout = child.stdout.take().unwrap();
+        let mut stderr = child.stderr.take().unwrap();
+
+        // Bytes moved in each direction, for the log line when git exits
+        let bytes_sent = Arc::new(AtomicU64::new(0));
+        let bytes_received = Arc::new(AtomicU64::new(0));
+
+        // Client data arrives through `data`; feed it to the process's stdin
+        let (stdin_tx, mut stdin_rx) = mpsc::channel::<Vec<u8>>(16);
+        self.channel_stdin.insert(channel, stdin_tx);
+        let received = bytes_received.clone();
+        tokio::spawn(async move {
+            while let Some(data) = stdin_rx.recv().await {
+                if stdin.write_all(&data).await.is_err() {
+                    break;
+                }
+                received.fetch_add(data.len() as u64, Ordering::Relaxed);
+            }
+            // Dropping stdin closes it once the client sends EOF
+        });
+
+        // Run the service outside the handler so the session keeps processing
+        // client messages (and flushing our output) while git runs
+        let handle = session.handle();
+        let progress = self.progress;
+        let buffer_size = self.buffer_size;
+        let repo_label = repo_path.to_string();
+        let git_cmd = git_cmd.to_string();
+        let user = self.user.clone().unwrap_or_default();
+        let ref_events = self.ref_events.clone();
+        let transfer_stats = self.transfer_stats.clone();
+        let service = service.to_string();
+        tokio::spawn(
+            async move {
+                let (output_tx, output_rx) = mpsc::channel::<Output>(4);
+
+                if progress {
+                    let msg = format!("agito: starting {} for {}\n", git_cmd, repo_label);
+                    let _ = output_tx
+                        .send((Some(SSH_EXTENDED_DATA_STDERR), msg.into_bytes()))
+                        .await;
+                }
+
+                // Forward stdout from git process to SSH channel
+                let stdout_tx = output_tx.clone();
+                let sent = bytes_sent.clone();
+                let forward_stdout = async move {
+                    git_io::copy_pkt_lines(&mut stdout, buffer_size, |chunk| {
+                        let tx = stdout_tx.clone();
+                        let sent = sent.clone();
+                        async move {
+                            let len = chunk.len() as u64;
+                            tx.send((None, chunk)).await.map_err(|_| {
+                                io::Error::new(io::ErrorKind::BrokenPipe, "channel closed")
+                            })?;
+                            sent.fetch_add(len, Ordering::Relaxed);
+                            Ok(())
+                        }
+                    })
+                    .await
+                };
+
+                // Forward stderr (git's progress output) to the client's stderr
+                let stderr_tx = output_tx;
+                let forward_stderr = async move {
+                    let mut buf = vec![0u8; buffer_size];
+                    loop {
+                        match stderr.read(&mut buf).await {
+                            Ok(0) | Err(_) => break,
+                            Ok(n) => {
+                                let data = buf[..n].to_vec();
+                                if stderr_tx
+                                    .send((Some(SSH_EXTENDED_DATA_STDERR), data))
+                                    .await
+                                    .is_err()
+                                {
+                                    break;
+                                }
+                            }
+                        }
+                    }
+                };
+
+                // The pipes are dropped as their forwarders finish, so git
+                // gets EPIPE instead of blocking if the client went away
+                let _ = tokio::join!(
+                    forward_stdout,
+                    forward_stderr,
+                    send_output(&mut output_channel, output_rx)
+                );
+
+                let exit_code = match child.wait().await {
+                    Ok(status) => status.code().unwrap_or(1),
+                    Err(e) => {
+                        tracing::error!("Failed to wait for {}: {}", git_cmd, e);
+                        1
+                    }
+                };
+                let bytes_sent = bytes_sent.load(Ordering::Relaxed);
+                let bytes_received = bytes_received.load(Ordering::Relaxed);
+                tracing::info!(
+                    repo = %repo_label,
+                    user = %user,
+                    bytes_sent,
+                    bytes_received,
+                    exit_code,
+                    "{} finished",
+                    git_cmd
+                );
+                transfer_stats.record(&service, bytes_sent, bytes_received);
+                if let Some(before) = refs_before {
+                    let after = crate::git::ref_targets(&full_path);
+                    ref_events.publish_changes(&full_path, &before, &after);
+                }
+                let _ = handle.exit_status_request(channel, exit_code as u32).await;
+                let _ = handle.eof(channel).await;
+                let _ = handle.close(channel).await;
+            }
+            .in_current_span(),
+        );
+
+        Ok(())
+    }
+
+    async fn handle_create_repo(
+        &mut self,
+        channel: ChannelId,
+        command: &str,
+        session: &mut Session,
+    ) -> Result<()> {
+        let parts: Vec<&str> = command.split_whitespace().skip(1).collect();
+        let Some((repo_name, dry_run)) = parse_create_args(&parts) else {
+            self.send_usage(channel, "agito-create-repo [--dry-run] <repo-name>", session);
+            return Ok(());
+        };
+
+        if self.refuse_creation(channel, session) {
+            return Ok(());
+        }
+
+        let repo_name = match self.new_repo_name(repo_name) {
+            Ok(name) => name,
+            Err(msg) => {
+                session.data(channel, format!("{}\n", msg).into_bytes().into());
+                session.exit_status_request(channel, 1);
+                session.eof(channel);
+                session.close(channel);
+                return Ok(());
+            }
+        };
+        let repo_path = self.repos_dir.join(&repo_name);
+
+        // Check if repository already exists
+        if repo_path.exists() {
+            let msg = format!("Repository already exists: {}\n", repo_name);
+            session.data(channel, msg.into_bytes().into());
+            session.exit_status_request(channel, 1);
+            session.eof(channel);
+            session.close(channel);
+            return Ok(());
+        }
+
+        if dry_run {
+            let msg = format!("Dry run: would create repository {}\n", repo_name);
+            session.data(channel, msg.into_bytes().into());
+            session.exit_status_request(channel, 0);
+            session.eof(channel);
+            session.close(channel);
+            return Ok(());
+        }
+
+        // Create the repository; a concurrent creation of the same name may
+        // have claimed it since the check above
+        match crate::git::create_bare_repo(&repo_path, self.user.as_deref()) {
+            Ok(true) => {}
+            Ok(false) => {
+                let msg = format!("Repository already exists: {}\n", repo_name);
+                session.data(channel, msg.into_bytes().into());
+                session.exit_status_request(channel, 1);
+                session.eof(channel);
+                session.close(channel);
+                return Ok(());
+            }
+            Err(e) => {
+                let msg = format!("Failed to create repository: {}\n", e);
+                session.data(channel, msg.into_bytes().into());
+                session.exit_status_request(channel, e.exit_code());
+                session.eof(channel);
+                session.close(channel);
+                return Ok(());
+            }
+        }
+
+        self.index_cache.invalidate();
+
+        let msg = format!("Repository created: {}\n", repo_name);
+        tracing::info!("Created repository: {:?}", repo_path);
+        session.data(channel, msg.into_bytes().into());
+        session.exit_status_request(channel, 0);
+        session.eof(channel);
+        session.close(channel);
+
+        Ok(())
+    }
+
+    /// `agito-import <repo-name> <source-url>`: create a repository that
+    /// mirrors every ref of a public HTTPS remote, streaming fetch progress
+    async fn handle_import(
+        &mut self,
+        channel: ChannelId,
+        command: &str,
+        session: &mut Session,
+    ) -> Result<()> {
+        if !self.allow_import {
+            let e = AgitoError::AccessDenied("Imports are disabled on this server".to_string());
+            self.send_error(channel, &e, session);
+            return Ok(());
+        }
+
+        let parts: Vec<&str> = command.split_whitespace().collect();
+        if parts.len() != 3 {
+            self.send_usage(channel, "agito-import <repo-name> <source-url>", session);
+            return Ok(());
+        }
+
+        if self.refuse_creation(channel, session) {
+            return Ok(());
+        }
+
+        let url = parts[2].to_string();
+        if !crate::git::valid_import_url(&url) {
+            let e = AgitoError::InvalidName(
+                "Only https:// URLs without credentials can be imported".to_string(),
+            );
+            self.send_error(channel, &e, session);
+            return Ok(());
+        }
+
+        let pin = match check_import_source(&url).await {
+            Ok(pin) => pin,
+            Err(e) => {
+                self.send_error(channel, &e, session);
+                return Ok(());
+            }
+        };
+
+        let repo_name = match self.new_repo_name(parts[1]) {
+            Ok(name) => name,
+            Err(msg) => {
+                self.send_error(channel, &AgitoError::InvalidName(msg), session);
+                return Ok(());
+            }
+        };
+        let repo_path = self.repos_dir.join(&repo_name);
+
+        match crate::git::create_bare_repo(&repo_path, self.user.as_deref()) {
+            Ok(true) => {}
+            Ok(false) => {
+                let e = AgitoError::InvalidName(format!("Repository already exists: {}", repo_name));
+                self.send_error(channel, &e, session);
+                return Ok(());
+            }
+            Err(e) => {
+                self.send_error(channel, &e, session);
+                return Ok(());
+            }
+        }
+
+        if let Err(e) = crate::git::add_mirror_remote(&repo_path, &url) {
+            let _ = fs::remove_dir_all(&repo_path);
+            self.send_error(channel, &e, session);
+            return Ok(());
+        }
+
+        let mut output_channel = self
+            .channel_handles
+            .remove(&channel)
+            .context("Import on a channel that is not open")?;
+
+        tracing::info!("Importing {} into {:?}", url, repo_path);
+
+        let handle = session.handle();
+        let index_cache = self.index_cache.clone();
+        let buffer_size = self.buffer_size;
+        tokio::spawn(
+            async move {
+                let (output_tx, output_rx) = mpsc::channel::<Output>(4);
+
+                let import = async move {
+                    let result =
+                        fetch_mirror(&repo_path, pin.as_deref(), &output_tx, buffer_size).await;
+                    let (msg, exit_code) = match result {
+                        Ok(()) => {
+                            index_cache.invalidate();
+                            tracing::info!("Imported repository: {:?}", repo_path);
+                            (format!("Repository imported: {}\n", repo_name), 0)
+                        }
+                        Err(e) => {
+                            tracing::warn!("Import of {} failed: {}", url, e);
+                            // Free the name so the import can be retried
+                            let _ = fs::remove_dir_all(&repo_path);
+                            (format!("Import failed: {}\n", e), e.exit_code())
+                        }
+                    };
+                    let _ = output_tx.send((None, msg.into_bytes())).await;
+                    exit_code
+                };
+
+                let (exit_code, ()) =
+                    tokio::join!(import, send_output(&mut output_channel, output_rx));
+
+                let _ = handle.exit_status_request(channel, exit_code).await;
+                let _ = handle.eof(channel).await;
+                let _ = handle.close(channel).await;
+            }
+            .in_current_span(),
+        );
+
+        Ok(())
+    }
+
+    async fn handle_config(
+        &mut self,
+        channel: ChannelId,
+        command: &str,
+        session: &mut Session,
+    ) -> Result<()> {
+        let parts: Vec<&str> = command.split_whitespace().collect();
+        if parts.len() != 4 {
+            self.send_usage(channel, "agito-config <repo-name> <key> <value>", session);
+            return Ok(());
+        }
+
+        let Some((repo_name, repo_path)) = self.resolve_or_report(channel, parts[1], session) else {
+            return Ok(());
+        };
+        let key = parts[2];
+        let value = parts[3];
+
+        if let Err(e) = crate::git::set_config(&repo_path, key, value, self.admin) {
+            self.send_error(channel, &e, session);
+            return Ok(());
+        }
+
+        tracing::info!("Set {} = {} in {:?}", key, value, repo_path);
+        let msg = format!("Set {} = {} in {}\n", key, value, repo_name);
+        session.data(channel, msg.into_bytes().into());
+        session.exit_status_request(channel, 0);
+        session.eof(channel);
+        session.close(channel);
+
+        Ok(())
+    }
+
+    async fn handle_set_head(
+        &mut self,
+        channel: ChannelId,
+        command: &str,
+        session: &mut Session,
+    ) -> Result<()> {
+        let parts: Vec<&str> = command.split_whitespace().collect();
+        if parts.len() != 3 {
+            self.send_usage(channel, "agito-set-head <repo-name> <branch>", session);
+            return Ok(());
+        }
+
+        let Some((repo_name, repo_path)) = self.resolve_or_report(channel, parts[1], session) else {
+            return Ok(());
+        };
+        let branch = parts[2];
 
-            if let Ok(auth_key) = russh_keys::parse_public_key_base64(line) {
-                if &auth_key == public_key {
-                    tracing::info!("User {} authenticated successfully", user);
-                    return Ok(Auth::Accept);
-                }
-            }
+        if let Err(e) = crate::git::set_head(&repo_path, branch) {
+            self.send_error(channel, &e, session);
+            return Ok(());
         }
 
-        Ok(Auth::Reject {
-            proceed_with_methods: None,
-        })
-    }
+        tracing::info!("Set HEAD of {:?} to {}", repo_path, branch);
+        let msg = format!("Default branch of {} is now {}\n", repo_name, branch);
+        session.data(channel, msg.into_bytes().into());
+        session.exit_status_request(channel, 0);
+        session.eof(channel);
+        session.close(channel);
 
-    async fn channel_open_session(
-        &mut self,
-        _channel: Channel<Msg>,
-        _session: &mut Session,
-    ) -> Result<bool, Self::Error> {
-        Ok(true)
+        Ok(())
     }
 
-    async fn exec_request(
+    /// `agito-branch <repo> create <name> <start-point>` and
+    /// `agito-branch <repo> delete <name>`: manage branches without a push.
+    /// Deletes follow the same branch protection as pushes.
+    async fn handle_branch(
         &mut self,
         channel: ChannelId,
-        data: &[u8],
+        command: &str,
         session: &mut Session,
-    ) -> Result<(), Self::Error> {
-        let command = String::from_utf8_lossy(data);
-        tracing::info!("Executing command: {}", command);
+    ) -> Result<()> {
+        let parts: Vec<&str> = command.split_whitespace().collect();
+        let action = match parts[..] {
+            [_, _, "create", branch, start_point] => Some((branch, Some(start_point))),
+            [_, _, "delete", branch] => Some((branch, None)),
+            _ => None,
+        };
+        let Some((branch, start_point)) = action else {
+            let usage = "agito-branch <repo-name> create <branch> <start-point>\n       \
+                         agito-branch <repo-name> delete <branch>";
+            self.send_usage(channel, usage, session);
+            return Ok(());
+        };
 
-        if command.starts_with("git-upload-pack") || command.starts_with("git-receive-pack") {
-            self.handle_git_command(channel, &command, session).await?;
-        } else if command.starts_with("agito-create-repo") {
-            self.handle_create_repo(channel, &command, session).await?;
-        } else {
-            let msg = format!("Unknown command: {}\n", command);
-            session.data(channel, msg.into_bytes().into());
-            session.exit_status_request(channel, 1);
-            session.eof(channel);
-            session.close(channel);
+        let Some((repo_name, repo_path)) = self.resolve_or_report(channel, parts[1], session) else {
+            return Ok(());
+        };
+
+        if crate::git::is_archived(&repo_path) {
+            let e = AgitoError::AccessDenied(format!(
+                "Repository {} is archived and read-only",
+                repo_name
+            ));
+            self.send_error(channel, &e, session);
+            return Ok(());
+        }
+
+        let result = match start_point {
+            Some(start_point) => {
+                crate::git::create_branch(&repo_path, branch, start_point, self.max_refs).map(
+                    |()| format!("Created branch {} in {} at {}\n", branch, repo_name, start_point),
+                )
+            }
+            None => match crate::hooks::protected_branches(&repo_path) {
+                Ok(patterns) if patterns.iter().any(|p| crate::hooks::branch_matches(p, branch)) => {
+                    Err(AgitoError::AccessDenied(format!(
+                        "Deleting protected branch '{}' is not allowed",
+                        branch
+                    )))
+                }
+                Ok(_) => crate::git::delete_branch(&repo_path, branch)
+                    .map(|()| format!("Deleted branch {} from {}\n", branch, repo_name)),
+                Err(e) => Err(AgitoError::Git(format!("{:#}", e))),
+            },
+        };
+
+        match result {
+            Ok(msg) => {
+                tracing::info!("{}", msg.trim_end());
+                self.index_cache.invalidate();
+                session.data(channel, msg.into_bytes().into());
+                session.exit_status_request(channel, 0);
+                session.eof(channel);
+                session.close(channel);
+            }
+            Err(e) => self.send_error(channel, &e, session),
         }
 
         Ok(())
     }
-}
 
-impl SessionHandler {
-    async fn handle_git_command(
+    async fn handle_set_topics(
         &mut self,
         channel: ChannelId,
         command: &str,
         session: &mut Session,
     ) -> Result<()> {
         let parts: Vec<&str> = command.split_whitespace().collect();
-        if parts.len() < 2 {
-            session.data(channel, b"Invalid git command\n".to_vec().into());
-            session.exit_status_request(channel, 1);
-            session.eof(channel);
-            session.close(channel);
+        if parts.len() < 2 || parts.len() > 3 {
+            self.send_usage(channel, "agito-set-topics <repo-name> [topic1,topic2,...]", session);
             return Ok(());
         }
 
-        let git_cmd = parts[0];
-        let repo_path = parts[1].trim_matches('\'').trim_matches('"');
-
-        // Clean and validate repo path
-        let repo_path = repo_path.trim_start_matches('/');
-        let full_path = self.repos_dir.join(repo_path);
-
-        // Security check: ensure path is within repos_dir
-        if !full_path.starts_with(&self.repos_dir) {
-            session.data(channel, b"Invalid repository path\n".to_vec().into());
-            session.exit_status_request(channel, 1);
-            session.eof(channel);
-            session.close(channel);
+        let Some((repo_name, repo_path)) = self.resolve_or_report(channel, parts[1], session) else {
             return Ok(());
-        }
+        };
+        let topics: Vec<String> = parts
+            .get(2)
+            .map(|topics| {
+                topics
+                    .split(',')
+                    .filter(|topic| !topic.is_empty())
+                    .map(str::to_string)
+                    .collect()
+            })
+            .unwrap_or_default();
 
-        // Check if repository exists
-        if !full_path.exists() {
-            let msg = format!("Repository not found: {}\n", repo_path);
-            session.data(channel, msg.into_bytes().into());
-            session.exit_status_request(channel, 1);
-            session.eof(channel);
-            session.close(channel);
+        if let Err(e) = crate::git::set_topics(&repo_path, &topics) {
+            self.send_error(channel, &e, session);
             return Ok(());
         }
 
-        // Execute git command
-        let mut child = Command::new(git_cmd)
-            .arg(&full_path)
-            .stdin(Stdio::piped())
-            .stdout(Stdio::piped())
-            .stderr(Stdio::piped())
-            .spawn()?;
+        self.index_cache.invalidate();
 
-        let _stdin = child.stdin.take().unwrap();
-        let mut stdout = child.stdout.take().unwrap();
-        let mut stderr = child.stderr.take().unwrap();
+        tracing::info!("Set topics of {:?} to {:?}", repo_path, topics);
+        let msg = format!("Topics of {}: {}\n", repo_name, topics.join(", "));
+        session.data(channel, msg.into_bytes().into());
+        session.exit_status_request(channel, 0);
+        session.eof(channel);
+        session.close(channel);
 
-        // NOTE: This is a simplified implementation. In a production system,
-        // stdin should be bidirectionally connected to the SSH channel to support
-        // git push operations. The original Go implementation had a similar limitation.
+        Ok(())
+    }
 
-        // Forward stdout from git process to SSH channel
-        let mut buf = vec![0u8; 8192];
-        loop {
-            match stdout.read(&mut buf).await {
-                Ok(0) => break,
-                Ok(n) => {
-                    session.data(channel, buf[..n].to_vec().into());
-                }
-                Err(_) => break,
-            }
+    /// `agito-hide` / `agito-unhide`: toggle whether the web viewer lists a
+    /// repository
+    async fn handle_set_hidden(
+        &mut self,
+        channel: ChannelId,
+        command: &str,
+        hidden: bool,
+        session: &mut Session,
+    ) -> Result<()> {
+        let parts: Vec<&str> = command.split_whitespace().collect();
+        if parts.len() != 2 {
+            self.send_usage(channel, &format!("{} <repo-name>", parts[0]), session);
+            return Ok(());
         }
 
-        // Forward stderr
-        let mut buf = vec![0u8; 8192];
-        loop {
-            match stderr.read(&mut buf).await {
-                Ok(0) => break,
-                Ok(n) => {
-                    session.data(channel, buf[..n].to_vec().into());
-                }
-                Err(_) => break,
-            }
+        let Some((repo_name, repo_path)) = self.resolve_or_report(channel, parts[1], session) else {
+            return Ok(());
+        };
+
+        if let Err(e) = crate::git::set_hidden(&repo_path, hidden) {
+            self.send_error(channel, &e, session);
+            return Ok(());
         }
 
-        let status = child.wait().await?;
-        let exit_code = status.code().unwrap_or(1);
-        session.exit_status_request(channel, exit_code as u32);
+        self.index_cache.invalidate();
+
+        tracing::info!("Set {:?} hidden: {}", repo_path, hidden);
+        let msg = if hidden {
+            format!("{} is now hidden from the web viewer\n", repo_name)
+        } else {
+            format!("{} is now shown in the web viewer\n", repo_name)
+        };
+        session.data(channel, msg.into_bytes().into());
+        session.exit_status_request(channel, 0);
         session.eof(channel);
         session.close(channel);
 
         Ok(())
     }
 
-    async fn handle_create_repo(
+    async fn handle_clean(
         &mut self,
         channel: ChannelId,
         command: &str,
         session: &mut Session,
     ) -> Result<()> {
         let parts: Vec<&str> = command.split_whitespace().collect();
-        if parts.len() < 2 {
-            session.data(channel, b"Usage: agito-create-repo <repo-name>\n".to_vec().into());
-            session.exit_status_request(channel, 1);
-            session.eof(channel);
-            session.close(channel);
+        if parts.len() != 2 {
+            self.send_usage(channel, "agito-clean <repo-name>", session);
             return Ok(());
         }
 
-        let mut repo_name = parts[1].to_string();
-
-        // Ensure repo name ends with .git
-        if !repo_name.ends_with(".git") {
-            repo_name.push_str(".git");
-        }
-
-        // Validate repo name
-        if repo_name.contains("..") || repo_name.contains('/') {
-            session.data(channel, b"Invalid repository name\n".to_vec().into());
-            session.exit_status_request(channel, 1);
-            session.eof(channel);
-            session.close(channel);
+        let Some((repo_name, repo_path)) = self.resolve_or_report(channel, parts[1], session) else {
             return Ok(());
-        }
-
-        let repo_path = self.repos_dir.join(&repo_name);
+        };
 
-        // Check if repository already exists
-        if repo_path.exists() {
-            let msg = format!("Repository already exists: {}\n", repo_name);
+        if crate::git::push_in_progress(&repo_path, self.stale_pack_age) {
+            let msg = format!("A push to {} is in progress; try again later\n", repo_name);
             session.data(channel, msg.into_bytes().into());
             session.exit_status_request(channel, 1);
             session.eof(channel);
@@ -316,18 +2157,15 @@ impl SessionHandler {
             return Ok(());
         }
 
-        // Create the repository
-        if let Err(e) = crate::git::init_bare_repo(&repo_path) {
-            let msg = format!("Failed to create repository: {}\n", e);
-            session.data(channel, msg.into_bytes().into());
-            session.exit_status_request(channel, 1);
-            session.eof(channel);
-            session.close(channel);
-            return Ok(());
-        }
+        let removed = match crate::git::clean_stale_packs(&repo_path, self.stale_pack_age) {
+            Ok(removed) => removed,
+            Err(e) => {
+                self.send_error(channel, &e, session);
+                return Ok(());
+            }
+        };
 
-        let msg = format!("Repository created: {}\n", repo_name);
-        tracing::info!("Created repository: {:?}", repo_path);
+        let msg = format!("Removed {} stale pack file(s) from {}\n", removed, repo_name);
         session.data(channel, msg.into_bytes().into());
         session.exit_status_request(channel, 0);
         session.eof(channel);
@@ -337,3 +2175,521 @@ impl SessionHandler {
     }
 }
 
+/// Refuse imports from hosts that resolve to loopback, private or other
+/// internal addresses, so `agito-import` can't be used to reach services
+/// only the server can see. Returns the pin that keeps git on the checked
+/// address.
+async fn check_import_source(url: &str) -> crate::error::Result<Option<String>> {
+    let Some((host, port)) = crate::git::import_authority(url) else {
+        return Err(AgitoError::InvalidName("Invalid import URL".to_string()));
+    };
+    let addrs: Vec<SocketAddr> = match tokio::net::lookup_host((host.as_str(), port)).await {
+        Ok(addrs) => addrs.collect(),
+        Err(_) => Vec::new(),
+    };
+    let Some(first) = addrs.first() else {
+        return Err(AgitoError::InvalidName(format!("Cannot resolve {}", host)));
+    };
+    if let Some(addr) = addrs.iter().find(|addr| !crate::net::is_public(addr.ip())) {
+        tracing::warn!("Refusing import from {}: resolves to {}", url, addr.ip());
+        return Err(AgitoError::AccessDenied(
+            "Imports from local or private addresses are not allowed".to_string(),
+        ));
+    }
+    Ok(crate::git::import_pin(&host, port, first.ip()))
+}
+
+/// Whether a line of `git fetch` stderr is progress rather than an error,
+/// whose text may come from wherever the source led git
+fn is_fetch_progress(line: &[u8]) -> bool {
+    !["fatal:", "error:", "warning:"].iter().any(|prefix| line.starts_with(prefix.as_bytes()))
+}
+
+/// Fetch everything from the mirror remote of the new repository at
+/// `repo_path`, forwarding git's progress as stderr, then point `HEAD` at
+/// the source's default branch
+async fn fetch_mirror(
+    repo_path: &Path,
+    pin: Option<&str>,
+    output: &mpsc::Sender<Output>,
+    buffer_size: usize,
+) -> crate::error::Result<()> {
+    let mut child = Command::from(crate::git::mirror_fetch_command(repo_path, pin))
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .io_context("Failed to execute git fetch")?;
+
+    // Forward progress line by line (progress lines end in `\r`), and keep
+    // errors in the server log only
+    let mut stderr = child.stderr.take().unwrap();
+    let mut pending = Vec::new();
+    let mut buf = vec![0u8; buffer_size];
+    loop {
+        match stderr.read(&mut buf).await {
+            Ok(0) | Err(_) => break,
+            Ok(n) => pending.extend_from_slice(&buf[..n]),
+        }
+        while let Some(end) = pending.iter().position(|&b| b == b'\n' || b == b'\r') {
+            let line: Vec<u8> = pending.drain(..=end).collect();
+            if is_fetch_progress(&line) {
+                let _ = output.send((Some(SSH_EXTENDED_DATA_STDERR), line)).await;
+            } else {
+                tracing::warn!("git fetch: {}", String::from_utf8_lossy(&line).trim_end());
+            }
+        }
+    }
+
+    let status = child.wait().await.io_context("Failed to wait for git fetch")?;
+    if !status.success() {
+        return Err(AgitoError::Git("Could not fetch from the source".to_string()));
+    }
+
+    if let Some(branch) = crate::git::mirror_head(repo_path, pin) {
+        crate::git::set_head(repo_path, &branch)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn valid_env_accepts_namespaces() {
+        assert!(valid_env("GIT_NAMESPACE", "tenant-a"));
+        assert!(valid_env("GIT_NAMESPACE", "org/team_1"));
+        assert!(!valid_env("GIT_NAMESPACE", ""));
+        assert!(!valid_env("GIT_NAMESPACE", "../other"));
+        assert!(!valid_env("GIT_NAMESPACE", "a//b"));
+        assert!(!valid_env("GIT_NAMESPACE", "a b"));
+        assert!(!valid_env("LD_PRELOAD", "evil.so"));
+    }
+
+    #[test]
+    fn valid_namespace_rejects_path_tricks() {
+        assert!(valid_namespace("alice"));
+        assert!(valid_namespace("bob.smith-2"));
+        assert!(!valid_namespace(""));
+        assert!(!valid_namespace(".."));
+        assert!(!valid_namespace(".hidden"));
+        assert!(!valid_namespace("a/b"));
+        assert!(!valid_namespace("a b"));
+    }
+
+    #[tokio::test]
+    async fn check_import_source_refuses_local_hosts() {
+        for url in ["https://127.0.0.1/a.git", "https://[::1]:8443/a.git", "https://localhost/a"] {
+            let result = check_import_source(url).await;
+            assert!(matches!(result, Err(AgitoError::AccessDenied(_))), "{}", url);
+        }
+        assert!(is_fetch_progress(b"Receiving objects:  50% (1/2)\r"));
+        assert!(!is_fetch_progress(b"fatal: unable to access 'https://x/': 403\n"));
+    }
+
+    #[test]
+    fn sanitize_command_strips_ansi_escapes() {
+        let command = "evil\x1b[2J\x1b]0;pwned\x07cmd\r\n";
+        assert_eq!(sanitize_command(command), "evil[2J]0;pwnedcmd");
+    }
+
+    #[test]
+    fn sanitize_command_truncates_long_input() {
+        let command = "a".repeat(MAX_ECHOED_COMMAND_LEN + 10);
+        let sanitized = sanitize_command(&command);
+        assert_eq!(sanitized.len(), MAX_ECHOED_COMMAND_LEN + 3);
+        assert!(sanitized.ends_with("..."));
+    }
+
+    #[test]
+    fn parse_authorized_key_reads_perms_option() {
+        let key = "AAAAC3NzaC1lZDI1NTE5AAAAIKybL8eZ4oY7Yo8Ntb1sfwXrF0ZBhGeZVQsmt9WbfsFA";
+
+        let bare = parse_authorized_key(key).unwrap();
+        assert_eq!(bare.permission, Permission::ReadWrite);
+
+        let typed = parse_authorized_key(&format!("ssh-ed25519 {} alice@laptop", key)).unwrap();
+        assert_eq!(typed.permission, Permission::ReadWrite);
+
+        let line = format!(r#"agito-perms="read" ssh-ed25519 {} ci"#, key);
+        let read_only = parse_authorized_key(&line).unwrap();
+        assert_eq!(read_only.permission, Permission::Read);
+        assert!(read_only.permission.allows("git-upload-pack"));
+        assert!(!read_only.permission.allows("git-receive-pack"));
+
+        let write_only = parse_authorized_key(&format!("agito-perms=write {}", key)).unwrap();
+        assert!(!write_only.permission.allows("git-upload-pack"));
+        assert!(write_only.permission.allows("git-receive-pack"));
+        assert!(!write_only.admin);
+
+        let admin = parse_authorized_key(&format!("agito-admin,agito-perms=rw {}", key)).unwrap();
+        assert!(admin.admin);
+        assert_eq!(admin.permission, Permission::ReadWrite);
+        assert_eq!(admin.user, None);
+
+        let user = parse_authorized_key(&format!(r#"agito-user="alice" {}"#, key)).unwrap();
+        assert_eq!(user.user.as_deref(), Some("alice"));
+        assert!(parse_authorized_key(&format!("agito-user=../bob {}", key)).is_none());
+
+        assert!(parse_authorized_key(&format!("agito-perms=admin ssh-ed25519 {}", key)).is_none());
+
+        // Restrictions agito can't enforce must not turn into full access
+        for options in [
+            r#"agito-perms=read,from="10.0.0.1 , 10.0.0.2""#,
+            r#"command="/bin/true""#,
+            "restrict",
+            "no-pty,no-port-forwarding",
+        ] {
+            let line = format!("{} ssh-ed25519 {} ci", options, key);
+            assert!(parse_authorized_key(&line).is_none(), "{}", options);
+        }
+    }
+
+    #[test]
+    fn resolve_repo_path_accepts_repository_names() {
+        let repos = Path::new("/srv/git");
+        let resolve = |raw| resolve_repo_path(repos, raw).unwrap();
+
+        assert_eq!(resolve("demo.git"), repos.join("demo.git"));
+        assert_eq!(resolve("/demo.git"), repos.join("demo.git"));
+        assert_eq!(resolve("alice/demo.git"), repos.join("alice/demo.git"));
+        assert_eq!(resolve("org/team/demo.git"), repos.join("org/team/demo.git"));
+        // Absolute paths are taken relative to the repository directory
+        assert_eq!(resolve("//etc/passwd"), repos.join("etc/passwd"));
+    }
+
+    #[test]
+    fn resolve_repo_path_rejects_traversal() {
+        let repos = Path::new("/srv/git");
+        for raw in [
+            "",
+            "/",
+            "..",
+            "../demo.git",
+            "/../demo.git",
+            "alice/../../etc",
+            "alice/./demo.git",
+            "alice//demo.git",
+            "demo.git/",
+            ".ssh",
+            "a/b/c/demo.git",
+        ] {
+            let result = resolve_repo_path(repos, raw);
+            assert!(
+                matches!(result, Err(AgitoError::InvalidName(_))),
+                "{:?} resolved to {:?}",
+                raw,
+                result
+            );
+        }
+    }
+
+    #[test]
+    fn resolve_repo_path_after_shell_quoting() {
+        let repos = Path::new("/srv/git");
+        let resolve = |command| {
+            let words = split_shell_words(command).unwrap();
+            resolve_repo_path(repos, &words[1])
+        };
+
+        assert_eq!(resolve("git-upload-pack '/demo.git'").unwrap(), repos.join("demo.git"));
+        assert_eq!(
+            resolve(r#"git-upload-pack "/my repo.git""#).unwrap(),
+            repos.join("my repo.git")
+        );
+        assert!(resolve("git-upload-pack '/../etc'").is_err());
+        assert!(resolve("git-upload-pack ''").is_err());
+        // Quotes that survive parsing are part of the name, not stripped
+        assert_eq!(
+            resolve(r#"git-upload-pack "'demo.git'""#).unwrap(),
+            repos.join("'demo.git'")
+        );
+    }
+
+    #[test]
+    fn parse_service_args_checks_flags() {
+        let (flags, path) = parse_service_args(
+            "git-upload-pack",
+            &["--strict", "--timeout=30", "repo.git"],
+        )
+        .unwrap();
+        assert_eq!(flags, ["--strict", "--timeout=30"]);
+        assert_eq!(path, "repo.git");
+
+        let (flags, _) = parse_service_args("git-receive-pack", &["repo.git"]).unwrap();
+        assert!(flags.is_empty());
+
+        assert!(parse_service_args("git-upload-pack", &["--timeout=", "repo.git"]).is_err());
+        assert!(parse_service_args("git-upload-pack", &["--upload-pack=sh", "repo.git"]).is_err());
+        assert!(parse_service_args("git-receive-pack", &["--strict", "repo.git"]).is_err());
+        assert!(parse_service_args("git-upload-pack", &["--strict"]).is_err());
+        assert!(parse_service_args("git-upload-pack", &["a.git", "b.git"]).is_err());
+    }
+
+    #[test]
+    fn parse_create_args_takes_one_name() {
+        assert_eq!(parse_create_args(&["app"]), Some(("app", false)));
+        assert_eq!(parse_create_args(&["--dry-run", "app"]), Some(("app", true)));
+        assert_eq!(parse_create_args(&["app", "--dry-run"]), Some(("app", true)));
+
+        assert_eq!(parse_create_args(&[]), None);
+        assert_eq!(parse_create_args(&["--dryrun", "app"]), None);
+        assert_eq!(parse_create_args(&["app", "other"]), None);
+        assert_eq!(parse_create_args(&["--dry-run", "--dry-run"]), None);
+    }
+
+    #[test]
+    fn split_shell_words_handles_quotes() {
+        let words = |command| split_shell_words(command).unwrap();
+
+        assert_eq!(words("git-upload-pack 'repo.git'"), ["git-upload-pack", "repo.git"]);
+        assert_eq!(
+            words("git-upload-pack --strict '/my repo.git'"),
+            ["git-upload-pack", "--strict", "/my repo.git"]
+        );
+        assert_eq!(
+            words(r#"git-receive-pack "team/a \"b\".git""#),
+            ["git-receive-pack", r#"team/a "b".git"#]
+        );
+        assert_eq!(words(r"git-upload-pack my\ repo.git"), ["git-upload-pack", "my repo.git"]);
+        assert_eq!(words("git-upload-pack  ''  x"), ["git-upload-pack", "", "x"]);
+        assert_eq!(words("a'b'\"c\"d"), ["abcd"]);
+
+        assert_eq!(split_shell_words("git-upload-pack 'repo.git"), None);
+        assert_eq!(split_shell_words(r#"git-upload-pack "repo.git\""#), None);
+        assert_eq!(split_shell_words("git-upload-pack repo.git\\"), None);
+    }
+
+    #[test]
+    fn authorized_keys_lookup_follows_file_changes() {
+        let alice = "AAAAC3NzaC1lZDI1NTE5AAAAIKybL8eZ4oY7Yo8Ntb1sfwXrF0ZBhGeZVQsmt9WbfsFA";
+        let bob = "AAAAC3NzaC1lZDI1NTE5AAAAIPwxlsebcEB0XpcnFEAWqMB7wSHnp/QgO5NDo6yaUJ2/";
+        let alice_key = russh_keys::parse_public_key_base64(alice).unwrap();
+        let bob_key = russh_keys::parse_public_key_base64(bob).unwrap();
+
+        let path = std::env::temp_dir().join(format!("agito-authkeys-{}", std::process::id()));
+        let first = format!("agito-perms=read ssh-ed25519 {}\nssh-ed25519 {}\n", alice, alice);
+        fs::write(&path, first).unwrap();
+        let keys = AuthorizedKeys::new(path.clone());
+
+        // The first entry for a key decides its permission
+        let permission = |key| keys.lookup(key).unwrap().map(|grant| grant.permission);
+        assert_eq!(permission(&alice_key), Some(Permission::Read));
+        assert_eq!(permission(&bob_key), None);
+
+        let second = format!("ssh-ed25519 {} alice\nagito-perms=write ssh-ed25519 {} bob\n", alice, bob);
+        fs::write(&path, second).unwrap();
+        assert_eq!(permission(&alice_key), Some(Permission::ReadWrite));
+        assert_eq!(permission(&bob_key), Some(Permission::Write));
+
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn read_authorized_keys_merges_directory() {
+        let dir = std::env::temp_dir().join(format!("agito-keys-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("team-b"), "key-b").unwrap();
+        fs::write(dir.join("team-a"), "key-a\n").unwrap();
+        fs::write(dir.join(".team-c.swp"), "key-c").unwrap();
+        fs::write(dir.join("team-d~"), "key-d").unwrap();
+
+        let keys = read_authorized_keys(&dir).unwrap();
+        assert_eq!(keys.lines().filter(|l| !l.is_empty()).collect::<Vec<_>>(), ["key-a", "key-b"]);
+
+        let file = dir.join("team-a");
+        assert_eq!(read_authorized_keys(&file).unwrap(), "key-a\n");
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn motd_is_silent_when_missing_or_empty() {
+        let path = std::env::temp_dir().join(format!("agito-motd-{}", std::process::id()));
+        let motd = Motd::new(path.clone());
+        assert!(motd.contents().is_none());
+
+        fs::write(&path, "").unwrap();
+        assert!(motd.contents().is_none());
+
+        fs::write(&path, "Welcome").unwrap();
+        touch_later(&path);
+        assert_eq!(motd.contents().as_deref(), Some(&b"Welcome\n"[..]));
+        let _ = fs::remove_file(&path);
+    }
+
+    /// An SSH server on an ephemeral port over a throwaway repository
+    /// directory, driven with the system `ssh` and `git` like a real client
+    struct TestServer {
+        root: PathBuf,
+        addr: SocketAddr,
+        client_key: PathBuf,
+        shutdown: Option<tokio::sync::oneshot::Sender<()>>,
+        running: tokio::task::JoinHandle<Result<()>>,
+    }
+
+    /// Stand-in for `agito-hook`, which unit tests don't build, so the
+    /// hooks of test repositories accept pushes
+    fn stub_hook_binary() {
+        static STUB: std::sync::OnceLock<PathBuf> = std::sync::OnceLock::new();
+        let path = STUB.get_or_init(|| {
+            use std::os::unix::fs::PermissionsExt;
+            let path = std::env::temp_dir().join(format!("agito-hook-{}", std::process::id()));
+            fs::write(&path, "#!/bin/sh\ncat >/dev/null\n").unwrap();
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+            path
+        });
+        crate::git::set_hook_binary(path.clone());
+    }
+
+    impl TestServer {
+        async fn start(name: &str) -> Self {
+            let root = std::env::temp_dir().join(format!("agito-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&root);
+            fs::create_dir_all(root.join("repos")).unwrap();
+            stub_hook_binary();
+
+            // An existing key skips the slow RSA generation in get_host_key
+            let host_key = root.join("host_key");
+            let client_key = root.join("client_key");
+            for key in [&host_key, &client_key] {
+                let status = std::process::Command::new("ssh-keygen")
+                    .args(["-q", "-t", "ed25519", "-N", "", "-f"])
+                    .arg(key)
+                    .status()
+                    .unwrap();
+                assert!(status.success());
+            }
+            let authorized_keys = root.join("authorized_keys");
+            fs::copy(client_key.with_extension("pub"), &authorized_keys).unwrap();
+
+            let server = Server::new(
+                "0".to_string(),
+                host_key,
+                authorized_keys,
+                root.join("repos"),
+                IndexCache::new(Duration::ZERO),
+            )
+            .bind_addr(IpAddr::from([127, 0, 0, 1]))
+            .bind()
+            .await
+            .unwrap();
+            let addr = server.local_addr().unwrap();
+
+            let (shutdown, shutdown_rx) = tokio::sync::oneshot::channel::<()>();
+            let running = tokio::spawn(server.serve(shutdown_rx));
+
+            Self {
+                root,
+                addr,
+                client_key,
+                shutdown: Some(shutdown),
+                running,
+            }
+        }
+
+        /// `ssh` invocation that trusts the server and offers only the test key
+        fn ssh_command(&self) -> String {
+            format!(
+                "ssh -F /dev/null -i {} -o IdentitiesOnly=yes -o BatchMode=yes \
+                 -o StrictHostKeyChecking=no -o UserKnownHostsFile=/dev/null -o LogLevel=ERROR",
+                self.client_key.display()
+            )
+        }
+
+        fn url(&self, repo: &str) -> String {
+            format!("ssh://git@127.0.0.1:{}/{}", self.addr.port(), repo)
+        }
+
+        /// Run an agito command over SSH, returning its exit code and output
+        async fn exec(&self, command: &str) -> (i32, String) {
+            let output = Command::new("sh")
+                .arg("-c")
+                .arg(format!(
+                    "{} -p {} git@127.0.0.1 {}",
+                    self.ssh_command(),
+                    self.addr.port(),
+                    command
+                ))
+                .output()
+                .await
+                .unwrap();
+            let text = String::from_utf8_lossy(&output.stdout).into_owned();
+            (output.status.code().unwrap_or(-1), text)
+        }
+
+        /// Run git in `dir` against the server, panicking if it fails
+        async fn git(&self, dir: &Path, args: &[&str]) -> String {
+            let output = Command::from(crate::git::git_command())
+                .arg("-C")
+                .arg(dir)
+                .args(args)
+                .env("GIT_SSH_COMMAND", self.ssh_command())
+                .env("GIT_AUTHOR_NAME", "a")
+                .env("GIT_AUTHOR_EMAIL", "a@example.com")
+                .env("GIT_COMMITTER_NAME", "a")
+                .env("GIT_COMMITTER_EMAIL", "a@example.com")
+                .output()
+                .await
+                .unwrap();
+            assert!(
+                output.status.success(),
+                "git {:?} failed: {}",
+                args,
+                String::from_utf8_lossy(&output.stderr)
+            );
+            String::from_utf8_lossy(&output.stdout).into_owned()
+        }
+
+        async fn stop(mut self) {
+            let _ = self.shutdown.take().unwrap().send(());
+            self.running.await.unwrap().unwrap();
+            let _ = fs::remove_dir_all(&self.root);
+        }
+    }
+
+    #[tokio::test]
+    async fn create_push_and_clone_over_ssh() {
+        let server = TestServer::start("e2e").await;
+
+        let (code, output) = server.exec("agito-create-repo demo.git").await;
+        assert_eq!(code, 0, "{}", output);
+        assert!(server.root.join("repos/demo.git/HEAD").exists());
+        let (code, _) = server.exec("agito-create-repo demo.git").await;
+        assert_ne!(code, 0);
+
+        let work = server.root.join("work");
+        fs::create_dir_all(&work).unwrap();
+        server.git(&work, &["init", "-q"]).await;
+        fs::write(work.join("README.md"), "# Demo\n").unwrap();
+        server.git(&work, &["add", "README.md"]).await;
+        server.git(&work, &["commit", "-q", "-m", "Initial commit"]).await;
+        server
+            .git(&work, &["push", "-q", &server.url("demo.git"), "HEAD:refs/heads/main"])
+            .await;
+
+        let clone = server.root.join("clone");
+        server
+            .git(
+                &server.root,
+                &["clone", "-q", "--branch", "main", &server.url("demo.git"), "clone"],
+            )
+            .await;
+        assert_eq!(fs::read_to_string(clone.join("README.md")).unwrap(), "# Demo\n");
+        assert_eq!(
+            server.git(&clone, &["log", "--format=%s"]).await.trim(),
+            "Initial commit"
+        );
+
+        server.stop().await;
+    }
+
+    /// Move the modification time forward so a rewrite within the
+    /// filesystem's timestamp granularity is still seen as a change
+    fn touch_later(path: &std::path::Path) {
+        let file = fs::File::options().append(true).open(path).unwrap();
+        file.set_modified(SystemTime::now() + Duration::from_secs(1)).unwrap();
+    }
+}
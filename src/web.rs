@@ -1,112 +1,752 @@
-use anyhow::Result;
+use crate::error::AgitoError;
+use crate::lfs::{self, LfsStore};
+use crate::maintenance::Maintenance;
+use crate::metrics::TransferStats;
+use crate::web_auth::{self, WebAcl, WebAuth};
+use crate::{git, git_io, net};
+use anyhow::{Context, Result};
 use axum::{
-    extract::{Path, State},
-    http::StatusCode,
-    response::{Html, IntoResponse, Response},
-    routing::get,
-    Router,
+    body::{Body, Bytes},
+    extract::{Path, Query, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        Html, IntoResponse, Redirect, Response,
+    },
+    routing::{get, patch, post},
+    Json, Router,
 };
-use std::fs;
+use futures::stream::{self, StreamExt, TryStreamExt};
+use axum_server::tls_rustls::RustlsConfig;
+use chrono::{DateTime, Utc};
+use data_encoding::HEXLOWER;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::net::IpAddr;
 use std::path::PathBuf;
-use std::process::Command;
-use std::sync::Arc;
-use tower_http::services::ServeDir;
+use std::sync::{Arc, Mutex};
+use std::process::Stdio;
+use std::time::{Duration, Instant};
+use tokio::io::AsyncReadExt;
+use tokio::process::{Child, ChildStdout, Command};
+use tokio::sync::broadcast;
+use tower::Service;
+use tower_http::cors::{AllowOrigin, CorsLayer};
+use tower_http::services::{ServeDir, ServeFile};
+use tower_http::trace::TraceLayer;
+
+/// Maximum number of repositories inspected at once when building the index
+const LIST_CONCURRENCY: usize = 16;
+
+/// Largest file shown inline by default; bigger files link to the raw endpoint
+pub const DEFAULT_MAX_BLOB_SIZE: u64 = 1024 * 1024;
+
+/// Icon served at `/favicon.ico`, built into the binary
+const FAVICON: &[u8] = include_bytes!("../web/favicon.ico");
+
+/// Target of the per-request events enabled by [`WebServer::access_log`], so
+/// they can be routed to their own log
+pub const ACCESS_LOG_TARGET: &str = "agito::access";
+
+/// URL the custom stylesheet is served at, under the `/static` mount
+const THEME_CSS_URL: &str = "/static/theme.css";
 
 #[derive(Clone)]
 pub struct WebServer {
     repos_dir: PathBuf,
+    index_cache: IndexCache,
+    ref_events: RefEvents,
+    transfer_stats: Option<TransferStats>,
+    readme_cache: ReadmeCache,
+    tls: Option<TlsFiles>,
+    theme_css: Option<PathBuf>,
+    static_dir: Option<PathBuf>,
+    max_blob_size: u64,
+    cors_origin: Option<HeaderValue>,
+    issue_url: Option<String>,
+    maintenance: Maintenance,
+    auth: Option<WebAuth>,
+    acl: Option<WebAcl>,
+    lfs: Option<LfsStore>,
+    public_url: Option<String>,
+    no_index: bool,
+    access_log: bool,
+}
+
+/// PEM certificate chain and private key used to serve HTTPS
+#[derive(Clone)]
+struct TlsFiles {
+    cert: PathBuf,
+    key: PathBuf,
+}
+
+/// Shared in-memory cache of the repository index.
+///
+/// The index is served from the cache for up to `ttl` after it was built, so
+/// repositories created or removed out-of-band (e.g. directly on disk) may take
+/// that long to show up. Repositories created over SSH invalidate the cache
+/// immediately when the SSH server shares the same handle. A zero TTL disables
+/// caching.
+#[derive(Clone)]
+pub struct IndexCache {
+    ttl: Duration,
+    entry: Arc<Mutex<Option<CachedIndex>>>,
+}
+
+struct CachedIndex {
+    built_at: Instant,
+    repos: Arc<Vec<Repository>>,
+}
+
+impl IndexCache {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            entry: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Drop the cached index so the next request rebuilds it
+    pub fn invalidate(&self) {
+        *self.entry.lock().unwrap() = None;
+    }
+
+    fn get(&self) -> Option<Arc<Vec<Repository>>> {
+        let entry = self.entry.lock().unwrap();
+        match entry.as_ref() {
+            Some(cached) if cached.built_at.elapsed() < self.ttl => Some(cached.repos.clone()),
+            _ => None,
+        }
+    }
+
+    fn store(&self, repos: Vec<Repository>) -> Arc<Vec<Repository>> {
+        let repos = Arc::new(repos);
+        if !self.ttl.is_zero() {
+            *self.entry.lock().unwrap() = Some(CachedIndex {
+                built_at: Instant::now(),
+                repos: repos.clone(),
+            });
+        }
+        repos
+    }
+}
+
+/// Ref updates buffered per subscriber; one that falls further behind skips
+/// the oldest
+const REF_EVENTS_CAPACITY: usize = 256;
+
+/// Ref updates made by pushes, streamed to `/repo/:name/events` subscribers.
+///
+/// The SSH server publishes what each push changed when it shares the same
+/// handle with the web server. Changes made any other way, e.g. directly on
+/// disk, are not seen.
+#[derive(Clone)]
+pub struct RefEvents {
+    sender: broadcast::Sender<Arc<RefUpdate>>,
+}
+
+/// A ref changed by a push
+#[derive(Debug, PartialEq, Serialize)]
+struct RefUpdate {
+    /// Canonical path of the repository, matched against subscriptions
+    #[serde(skip)]
+    repo: PathBuf,
+    #[serde(rename = "ref")]
+    refname: String,
+    /// Previous target, all zeros for a created ref
+    old: String,
+    /// New target, all zeros for a deleted ref
+    new: String,
+}
+
+impl RefEvents {
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(REF_EVENTS_CAPACITY);
+        Self { sender }
+    }
+
+    /// Whether anyone is listening, so publishers can skip snapshotting refs
+    pub fn has_subscribers(&self) -> bool {
+        self.sender.receiver_count() > 0
+    }
+
+    /// Publish every ref that differs between two [`git::ref_targets`]
+    /// snapshots of `repo_path`
+    pub fn publish_changes(
+        &self,
+        repo_path: &std::path::Path,
+        before: &BTreeMap<String, String>,
+        after: &BTreeMap<String, String>,
+    ) {
+        let repo = repo_path.canonicalize().unwrap_or_else(|_| repo_path.to_path_buf());
+        for (refname, old, new) in ref_changes(before, after) {
+            let _ = self.sender.send(Arc::new(RefUpdate {
+                repo: repo.clone(),
+                refname,
+                old,
+                new,
+            }));
+        }
+    }
+
+    fn subscribe(&self) -> broadcast::Receiver<Arc<RefUpdate>> {
+        self.sender.subscribe()
+    }
+}
+
+impl Default for RefEvents {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// `(ref, old, new)` for every ref created, moved or deleted between two
+/// snapshots
+fn ref_changes(
+    before: &BTreeMap<String, String>,
+    after: &BTreeMap<String, String>,
+) -> Vec<(String, String, String)> {
+    let null = crate::hooks::NULL_SHA;
+    let mut changes: Vec<(String, String, String)> = after
+        .iter()
+        .filter(|(refname, new)| before.get(*refname) != Some(new))
+        .map(|(refname, new)| {
+            let old = before.get(refname).map_or(null, String::as_str);
+            (refname.clone(), old.to_string(), new.clone())
+        })
+        .collect();
+    changes.extend(
+        before
+            .iter()
+            .filter(|(refname, _)| !after.contains_key(*refname))
+            .map(|(refname, old)| (refname.clone(), old.clone(), null.to_string())),
+    );
+    changes
+}
+
+/// Rendered READMEs kept in memory
+const README_CACHE_SIZE: usize = 256;
+
+/// README names tried, in order, in each directory
+const README_NAMES: &[&str] = &["README.md", "README", "Readme.md", "readme.md"];
+
+/// Markdown READMEs rendered to HTML, keyed by blob id.
+///
+/// A blob id names exact content, so entries never go stale; once the cache is
+/// full the least recently used entry makes room for the next.
+#[derive(Clone)]
+struct ReadmeCache {
+    capacity: usize,
+    entries: Arc<Mutex<ReadmeEntries>>,
+}
+
+#[derive(Default)]
+struct ReadmeEntries {
+    /// Blob id to when it was last used and its rendered HTML
+    map: HashMap<String, (u64, Arc<str>)>,
+    clock: u64,
+}
+
+impl ReadmeCache {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: Arc::new(Mutex::new(ReadmeEntries::default())),
+        }
+    }
+
+    fn get(&self, id: &str) -> Option<Arc<str>> {
+        let mut entries = self.entries.lock().unwrap();
+        entries.clock += 1;
+        let clock = entries.clock;
+        entries.map.get_mut(id).map(|(used, html)| {
+            *used = clock;
+            html.clone()
+        })
+    }
+
+    fn insert(&self, id: String, html: String) -> Arc<str> {
+        let html: Arc<str> = html.into();
+        let mut entries = self.entries.lock().unwrap();
+        if entries.map.len() >= self.capacity && !entries.map.contains_key(&id) {
+            let oldest = entries
+                .map
+                .iter()
+                .min_by_key(|(_, (used, _))| *used)
+                .map(|(id, _)| id.clone());
+            if let Some(oldest) = oldest {
+                entries.map.remove(&oldest);
+            }
+        }
+        entries.clock += 1;
+        let clock = entries.clock;
+        entries.map.insert(id, (clock, html.clone()));
+        html
+    }
 }
 
+#[derive(Clone, Serialize)]
 pub struct Repository {
     name: String,
+    #[allow(dead_code)]
+    #[serde(skip)]
     path: PathBuf,
     description: String,
+    /// `<short hash> - <subject>` of the commit `HEAD` points to
     last_commit: String,
-    branches: Vec<String>,
+    /// Committer date of that commit
+    last_commit_date: Option<DateTime<Utc>>,
+    branch_count: usize,
+    /// Commits on any branch in the last [`RECENT_DAYS`] days
+    recent_commits: usize,
+    last_activity: Option<DateTime<Utc>>,
+    metadata: Option<git::RepoMetadata>,
 }
 
+/// Window for the recent activity shown above the index
+const RECENT_DAYS: u32 = 7;
+
 impl WebServer {
-    pub fn new(repos_dir: PathBuf) -> Self {
-        Self { repos_dir }
+    pub fn new(repos_dir: PathBuf, index_cache: IndexCache) -> Self {
+        Self {
+            repos_dir,
+            index_cache,
+            ref_events: RefEvents::new(),
+            transfer_stats: None,
+            readme_cache: ReadmeCache::new(README_CACHE_SIZE),
+            tls: None,
+            theme_css: None,
+            static_dir: None,
+            max_blob_size: DEFAULT_MAX_BLOB_SIZE,
+            cors_origin: None,
+            issue_url: None,
+            maintenance: Maintenance::disabled(),
+            auth: None,
+            acl: None,
+            lfs: None,
+            public_url: None,
+            no_index: false,
+            access_log: false,
+        }
+    }
+
+    /// Stream ref updates published to `events` from `/repo/:name/events`;
+    /// share the handle with the SSH server so its pushes show up
+    pub fn ref_events(mut self, events: RefEvents) -> Self {
+        self.ref_events = events;
+        self
+    }
+
+    /// Serve `stats` at `/metrics` for Prometheus; share the handle with the
+    /// SSH server, which records its transfers there
+    pub fn transfer_stats(mut self, stats: TransferStats) -> Self {
+        self.transfer_stats = Some(stats);
+        self
+    }
+
+    /// Serve HTTPS using a PEM certificate chain and private key
+    pub fn tls(mut self, cert: PathBuf, key: PathBuf) -> Self {
+        self.tls = Some(TlsFiles { cert, key });
+        self
+    }
+
+    /// Link a custom stylesheet from every page, after the built-in styles so
+    /// its rules take precedence
+    pub fn theme_css(mut self, path: PathBuf) -> Self {
+        self.theme_css = Some(path);
+        self
+    }
+
+    /// Serve the files in `dir` under `/static`. Pages carry their own
+    /// styles, so nothing is served there without it
+    pub fn static_dir(mut self, dir: PathBuf) -> Self {
+        self.static_dir = Some(dir);
+        self
+    }
+
+    /// Only show each user the repositories `acl` lists for them, signing in
+    /// with the credentials from [`WebServer::auth`]
+    pub fn acl(mut self, acl: WebAcl) -> Self {
+        self.acl = Some(acl);
+        self
+    }
+
+    /// Serve the Git LFS batch API under `/repo/<name>/info/lfs`, keeping
+    /// objects in `store`. Anyone who can see a repository may download its
+    /// objects; uploading takes the credentials from [`WebServer::auth`]
+    pub fn lfs(mut self, store: LfsStore) -> Self {
+        self.lfs = Some(store);
+        self
+    }
+
+    /// Scheme and host clients reach the server at, e.g.
+    /// `https://git.example.com`, for the absolute URLs in the sitemap,
+    /// robots.txt and LFS responses. Without it the sitemap isn't served and
+    /// LFS transfers are refused.
+    pub fn public_url(mut self, url: String) -> Self {
+        self.public_url = Some(url.trim_end_matches('/').to_string());
+        self
+    }
+
+    /// Ask crawlers to stay away from the whole site in `/robots.txt`,
+    /// rather than only from the expensive pages
+    pub fn no_index(mut self, enabled: bool) -> Self {
+        self.no_index = enabled;
+        self
+    }
+
+    /// Emit an [`ACCESS_LOG_TARGET`] event for every request with its method,
+    /// path, repository, status and duration
+    pub fn access_log(mut self, enabled: bool) -> Self {
+        self.access_log = enabled;
+        self
+    }
+
+    /// Refuse to load files larger than `bytes` for display in a page
+    pub fn max_blob_size(mut self, bytes: u64) -> Self {
+        self.max_blob_size = bytes;
+        self
+    }
+
+    /// Allow browsers on `origin` (or any origin, for `*`) to call the
+    /// `/api` routes
+    pub fn cors_origin(mut self, origin: HeaderValue) -> Self {
+        self.cors_origin = Some(origin);
+        self
+    }
+
+    /// Link `#123` references in commit messages to `template`, where `{id}`
+    /// is replaced by the number
+    pub fn issue_url(mut self, template: String) -> Self {
+        self.issue_url = Some(template);
+        self
+    }
+
+    /// Show a banner on every page and refuse edits while the server is in
+    /// maintenance
+    pub fn maintenance(mut self, maintenance: Maintenance) -> Self {
+        self.maintenance = maintenance;
+        self
+    }
+
+    /// Enable editing endpoints for users listed in `auth`; without it they
+    /// are refused
+    pub fn auth(mut self, auth: WebAuth) -> Self {
+        self.auth = Some(auth);
+        self
     }
 
-    pub async fn start(self, port: &str) -> Result<()> {
-        let app = Router::new()
+    pub async fn start(self, bind_addr: IpAddr, port: &str) -> Result<()> {
+        let tls = self.tls.clone();
+        let app = self.router();
+
+        let listener = net::bind(bind_addr, port)?;
+        tracing::info!("Web server listening on {}", listener.local_addr()?);
+
+        match tls {
+            Some(tls) => {
+                let config = load_tls_config(&tls).await?;
+                tracing::info!("Visit https://localhost:{} to view repositories", port);
+                axum_server::from_tcp_rustls(listener.into_std()?, config)
+                    .serve(app.into_make_service())
+                    .await?;
+            }
+            None => {
+                tracing::info!("Visit http://localhost:{} to view repositories", port);
+                axum::serve(listener, app).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Build the HTTP router, for serving connections accepted elsewhere
+    pub fn router(self) -> Router {
+        let access_log = self.access_log;
+        let mut router = Router::new();
+        if let Some(css) = &self.theme_css {
+            router = router.route_service(THEME_CSS_URL, ServeFile::new(css));
+        }
+        if let Some(dir) = &self.static_dir {
+            router = router.nest_service("/static", ServeDir::new(dir));
+        }
+
+        let mut api = Router::new()
+            .route("/api/repos", get(handle_api_repos))
+            .route("/api/repos/:name", get(handle_api_repo));
+        if let Some(cors) = self.cors_layer() {
+            api = api.layer(cors);
+        }
+
+        if self.transfer_stats.is_some() {
+            router = router.route("/metrics", get(handle_metrics));
+        }
+
+        let mut lfs = Router::new();
+        if self.lfs.is_some() {
+            lfs = lfs
+                .route("/repo/:name/info/lfs/objects/batch", post(handle_lfs_batch))
+                .route(
+                    "/repo/:name/info/lfs/objects/:oid",
+                    get(handle_lfs_download).put(handle_lfs_upload),
+                );
+        }
+
+        let router = router
+            .merge(api)
+            .merge(lfs)
             .route("/", get(handle_index))
+            .route("/sitemap.xml", get(handle_sitemap))
+            .route("/robots.txt", get(handle_robots))
+            .route("/favicon.ico", get(handle_favicon))
+            .route("/manifest.json", get(handle_manifest))
+            .route("/login", get(handle_login))
             .route("/repo/:name", get(handle_repo))
+            .route("/repo/:name/refs", get(handle_refs))
+            .route("/repo/:name/activity.json", get(handle_activity))
+            .route("/repo/:name/events", get(handle_events))
+            .route(
+                "/repo/:name/description",
+                patch(handle_set_description),
+            )
+            .route("/repo/:name/search", get(handle_search))
+            .route("/repo/:name/history/:rev/*path", get(handle_history))
+            .route("/repo/:name/commit/:hash", get(handle_commit))
+            .route("/repo/:name/raw/*path", get(handle_raw))
             .route("/repo/:name/*path", get(handle_repo))
-            .nest_service("/static", ServeDir::new("web/static"))
             .with_state(Arc::new(self));
 
-        let addr = format!("0.0.0.0:{}", port);
-        tracing::info!("Web server listening on {}", addr);
-        tracing::info!("Visit http://localhost:{} to view repositories", port);
+        if !access_log {
+            return router;
+        }
+        router.layer(
+            TraceLayer::new_for_http()
+                .make_span_with(|request: &axum::extract::Request| {
+                    let path = request.uri().path();
+                    tracing::info_span!(
+                        target: ACCESS_LOG_TARGET,
+                        "request",
+                        method = %request.method(),
+                        path,
+                        repo = access_log_repo(path).as_deref().unwrap_or("-"),
+                    )
+                })
+                .on_request(())
+                .on_response(|response: &Response, latency: Duration, _: &tracing::Span| {
+                    tracing::info!(
+                        target: ACCESS_LOG_TARGET,
+                        status = response.status().as_u16(),
+                        ms = latency.as_millis() as u64,
+                    );
+                })
+                .on_failure(()),
+        )
+    }
 
-        let listener = tokio::net::TcpListener::bind(&addr).await?;
-        axum::serve(listener, app).await?;
+    /// The user authenticated by the request's Basic credentials. Handlers
+    /// call this once and pass the user on, since checking a password is slow.
+    async fn require_user(&self, headers: &HeaderMap) -> Result<String, AuthRejection> {
+        let Some(auth) = self.auth.clone() else {
+            return Err(AuthRejection::Disabled);
+        };
+        let Some(authorization) = headers
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string)
+        else {
+            return Err(AuthRejection::Unauthenticated);
+        };
 
-        Ok(())
+        // Argon2 would hold up every other request on this worker thread
+        tokio::task::spawn_blocking(move || auth.authenticate(&authorization))
+            .await
+            .ok()
+            .flatten()
+            .ok_or(AuthRejection::Unauthenticated)
     }
 
-    fn list_repositories(&self) -> Result<Vec<Repository>> {
-        let mut repos = Vec::new();
+    /// Repository patterns `user`, or a visitor without credentials, may see
+    /// under the web ACL, or `None` when there is no ACL and everything is
+    /// visible
+    fn acl_patterns(&self, user: Option<&str>) -> Option<Vec<String>> {
+        Some(self.acl.as_ref()?.patterns(user))
+    }
 
-        let entries = fs::read_dir(&self.repos_dir)?;
+    /// The repository index narrowed to what the request may see
+    async fn visible_repositories(&self, headers: &HeaderMap) -> Result<Arc<Vec<Repository>>> {
+        let repos = self.cached_repositories().await?;
+        let Some(acl) = &self.acl else {
+            return Ok(repos);
+        };
+        let user = self.require_user(headers).await.ok();
+        let patterns = acl.patterns(user.as_deref());
+        Ok(Arc::new(
+            repos
+                .iter()
+                .filter(|repo| web_auth::repo_allowed(&patterns, &repo.name))
+                .cloned()
+                .collect(),
+        ))
+    }
 
-        for entry in entries {
-            let entry = entry?;
-            if !entry.file_type()?.is_dir() {
-                continue;
-            }
+    /// Path of the repository `name` names, if it exists and may be shown to
+    /// the request; see [`Self::repo_visible_to`]
+    async fn visible_repo(&self, name: &str, headers: &HeaderMap) -> Option<PathBuf> {
+        let user = self.require_user(headers).await.ok();
+        self.repo_visible_to(name, user.as_deref())
+    }
 
-            let repo_path = entry.path();
+    /// Path of the repository `name` names, if it exists and may be shown to
+    /// `user`, or to a visitor without credentials when `None`.
+    ///
+    /// Hidden repositories count as missing unless [`Self::sees_hidden`]
+    /// holds for the user, and so do repositories the web ACL doesn't list
+    /// for the user.
+    fn repo_visible_to(&self, name: &str, user: Option<&str>) -> Option<PathBuf> {
+        if !git::valid_repo_path(name) {
+            return None;
+        }
 
-            // Check if it's a git repository
-            if !repo_path.join("HEAD").exists() {
-                continue;
+        let repo_path = self.repos_dir.join(name);
+        if !repo_path.exists() {
+            return None;
+        }
+        if git::is_hidden(&repo_path) && !user.is_some_and(|user| self.sees_hidden(user)) {
+            return None;
+        }
+        if let Some(patterns) = self.acl_patterns(user) {
+            if !web_auth::repo_allowed(&patterns, name) {
+                return None;
             }
+        }
+        Some(repo_path)
+    }
 
-            let mut repo = Repository {
-                name: entry.file_name().to_string_lossy().to_string(),
-                path: repo_path.clone(),
-                description: String::new(),
-                last_commit: String::new(),
-                branches: Vec::new(),
-            };
+    /// Whether signed-in `user` may open hidden repositories: with a web ACL
+    /// only its administrators, otherwise everyone signed in, who may
+    /// already change every repository
+    fn sees_hidden(&self, user: &str) -> bool {
+        match &self.acl {
+            Some(acl) => acl.is_admin(user),
+            None => true,
+        }
+    }
 
-            // Get description
-            let desc_path = repo_path.join("description");
-            if let Ok(desc) = fs::read_to_string(&desc_path) {
-                let desc = desc.trim().to_string();
-                if desc != "Unnamed repository; edit this file 'description' to name the repository."
-                {
-                    repo.description = desc;
-                }
-            }
+    /// Whether signed-in `user` may change repository `name`: any repository
+    /// without a web ACL, otherwise only those the ACL lists under the
+    /// user's own name, since the `*` patterns are open to everyone
+    fn may_write(&self, name: &str, user: &str) -> bool {
+        match &self.acl {
+            Some(acl) => web_auth::repo_allowed(&acl.own_patterns(user), name),
+            None => true,
+        }
+    }
 
-            // Get last commit info
-            let output = Command::new("git")
-                .arg("-C")
-                .arg(&repo_path)
-                .arg("log")
-                .arg("-1")
-                .arg("--format=%h - %s (%cr)")
-                .output();
-
-            if let Ok(output) = output {
-                if output.status.success() {
-                    repo.last_commit = String::from_utf8_lossy(&output.stdout).trim().to_string();
-                }
-            }
+    /// CORS policy for the JSON API, if an origin is configured
+    fn cors_layer(&self) -> Option<CorsLayer> {
+        let origin = self.cors_origin.clone()?;
+        let allow_origin = if origin == "*" {
+            AllowOrigin::any()
+        } else {
+            AllowOrigin::exact(origin)
+        };
+
+        Some(
+            CorsLayer::new()
+                .allow_origin(allow_origin)
+                .allow_methods([axum::http::Method::GET]),
+        )
+    }
+
+    /// Add the custom stylesheet link and the maintenance banner, when they
+    /// apply, to a rendered page
+    /// A themed page that clients must revalidate before every reuse, with
+    /// an ETag of its content so unchanged pages are answered with a 304.
+    ///
+    /// The tag covers everything the page shows, including what depends on
+    /// who is signed in, so a cached copy never outlives a login change.
+    fn revalidated_page(&self, headers: &HeaderMap, html: String) -> Response {
+        let Html(html) = self.themed(html);
+        let etag = etag(html.as_bytes());
+        // Pages may hold repositories other visitors can't see
+        let cache_control = if self.auth.is_some() || self.acl.is_some() {
+            "private, no-cache"
+        } else {
+            "no-cache"
+        };
+
+        let mut response = if etag_matches(headers, &etag) {
+            StatusCode::NOT_MODIFIED.into_response()
+        } else {
+            Html(html).into_response()
+        };
+        let headers = response.headers_mut();
+        headers.insert(header::CACHE_CONTROL, HeaderValue::from_static(cache_control));
+        if let Ok(value) = HeaderValue::from_str(&etag) {
+            headers.insert(header::ETAG, value);
+        }
+        response
+    }
+
+    fn themed(&self, mut html: String) -> Html<String> {
+        if self.theme_css.is_some() {
+            let link = format!(r#"<link rel="stylesheet" href="{}">"#, THEME_CSS_URL);
+            html = html.replacen("</head>", &format!("    {}\n</head>", link), 1);
+        }
 
-            repos.push(repo);
+        if self.maintenance.is_enabled() {
+            let banner = format!(
+                r#"<div class="maintenance" style="background: #fff3cd; border: 1px solid #ffe08a; padding: 10px; margin-bottom: 20px;">{}</div>"#,
+                html_escape(self.maintenance.message())
+            );
+            html = html.replacen("<body>", &format!("<body>\n    {}", banner), 1);
         }
 
+        Html(html)
+    }
+
+    async fn list_repositories(&self) -> Result<Vec<Repository>> {
+        // Repositories may be nested, e.g. in per-user directories
+        let repos_dir = self.repos_dir.clone();
+        let candidates: Vec<(String, PathBuf)> =
+            tokio::task::spawn_blocking(move || git::discover_repos(&repos_dir))
+                .await?
+                .into_iter()
+                .map(|name| {
+                    let repo_path = self.repos_dir.join(&name);
+                    (name, repo_path)
+                })
+                .filter(|(_, repo_path)| !git::is_hidden(repo_path))
+                .collect();
+
+        // Gather per-repo metadata concurrently; each one shells out to git
+        let repos: Vec<Option<Repository>> = stream::iter(candidates)
+            .map(|(name, repo_path)| {
+                tokio::task::spawn_blocking(move || load_repository(name, repo_path))
+            })
+            .buffer_unordered(LIST_CONCURRENCY)
+            .try_collect()
+            .await?;
+        let mut repos: Vec<Repository> = repos.into_iter().flatten().collect();
+
+        repos.sort_by(|a, b| a.name.cmp(&b.name));
+
         Ok(repos)
     }
 
+    /// Return the repository index, rebuilding it if the cache is stale
+    async fn cached_repositories(&self) -> Result<Arc<Vec<Repository>>> {
+        if let Some(repos) = self.index_cache.get() {
+            return Ok(repos);
+        }
+
+        let repos = self.list_repositories().await?;
+        Ok(self.index_cache.store(repos))
+    }
+
     fn get_branches(&self, repo_path: &PathBuf) -> Result<Vec<String>> {
-        let output = Command::new("git")
+        let output = git::git_command()
             .arg("-C")
             .arg(repo_path)
             .arg("branch")
@@ -130,42 +770,25 @@ impl WebServer {
         Ok(branches)
     }
 
-    fn get_commits(&self, repo_path: &PathBuf, limit: usize) -> Result<Vec<CommitInfo>> {
-        let output = Command::new("git")
+    fn get_commits(&self, repo_path: &PathBuf, limit: usize) -> Result<Vec<git::CommitInfo>> {
+        let output = git::log_command()
             .arg("-C")
             .arg(repo_path)
             .arg("log")
             .arg(format!("--max-count={}", limit))
-            .arg("--format=%H|%an|%ar|%s")
+            .arg(git::log_format())
             .output()?;
 
         if !output.status.success() {
             return Ok(Vec::new());
         }
 
-        let commits: Vec<CommitInfo> = String::from_utf8_lossy(&output.stdout)
-            .lines()
-            .filter_map(|line| {
-                let parts: Vec<&str> = line.splitn(4, '|').collect();
-                if parts.len() == 4 {
-                    Some(CommitInfo {
-                        hash: parts[0][..8.min(parts[0].len())].to_string(),
-                        author: parts[1].to_string(),
-                        date: parts[2].to_string(),
-                        message: parts[3].to_string(),
-                    })
-                } else {
-                    None
-                }
-            })
-            .collect();
-
-        Ok(commits)
+        Ok(git::parse_log(&String::from_utf8_lossy(&output.stdout)))
     }
 
     fn list_files(&self, repo_path: &PathBuf, branch: &str, path: &str) -> Result<Vec<FileInfo>> {
         let tree_path = format!("{}:{}", branch, path);
-        let output = Command::new("git")
+        let output = git::git_command()
             .arg("-C")
             .arg(repo_path)
             .arg("ls-tree")
@@ -186,6 +809,7 @@ impl WebServer {
                     Some(FileInfo {
                         name,
                         file_type: file_type.to_string(),
+                        object: parts[2].to_string(),
                     })
                 } else {
                     None
@@ -196,9 +820,22 @@ impl WebServer {
         Ok(files)
     }
 
+    /// Read a file for display, failing with [`git::BlobTooLarge`] rather than
+    /// buffering anything over the configured size limit
     fn get_file_content(&self, repo_path: &PathBuf, branch: &str, path: &str) -> Result<String> {
+        let Some(size) = git::blob_size(repo_path, branch, path) else {
+            anyhow::bail!("Failed to get file content");
+        };
+        if size > self.max_blob_size {
+            return Err(git::BlobTooLarge {
+                size,
+                limit: self.max_blob_size,
+            }
+            .into());
+        }
+
         let blob_path = format!("{}:{}", branch, path);
-        let output = Command::new("git")
+        let output = git::git_command()
             .arg("-C")
             .arg(repo_path)
             .arg("show")
@@ -212,12 +849,42 @@ impl WebServer {
         Ok(String::from_utf8_lossy(&output.stdout).to_string())
     }
 
-    fn get_readme(&self, repo_path: &PathBuf, branch: &str) -> Option<String> {
-        let readme_names = ["README.md", "README", "Readme.md", "readme.md"];
+    /// Find the README in directory `dir` (empty for the root), returning
+    /// its path and either its content as HTML or the reason it can't be shown.
+    /// Markdown is rendered once per blob and then served from the cache.
+    fn get_readme(
+        &self,
+        repo_path: &PathBuf,
+        branch: &str,
+        dir: &str,
+    ) -> Option<(String, Result<String>)> {
+        for name in README_NAMES {
+            let path = if dir.is_empty() {
+                name.to_string()
+            } else {
+                format!("{}/{}", dir, name)
+            };
+            let Some(id) = git::object_id(repo_path, branch, &path) else {
+                continue;
+            };
+            let markdown = path.ends_with(".md");
+            if markdown {
+                if let Some(html) = self.readme_cache.get(&id) {
+                    return Some((path, Ok(html.to_string())));
+                }
+            }
 
-        for name in &readme_names {
-            if let Ok(content) = self.get_file_content(repo_path, branch, name) {
-                return Some(content);
+            match self.get_file_content(repo_path, branch, &path) {
+                Ok(content) if content.is_empty() => return Some((path, Ok(content))),
+                Ok(content) if markdown => {
+                    let html = self.readme_cache.insert(id, render_markdown(&content));
+                    return Some((path, Ok(html.to_string())));
+                }
+                Ok(content) => {
+                    return Some((path, Ok(format!("<pre>{}</pre>", html_escape(&content)))))
+                }
+                Err(e) if e.is::<git::BlobTooLarge>() => return Some((path, Err(e))),
+                Err(_) => continue,
             }
         }
 
@@ -225,21 +892,146 @@ impl WebServer {
     }
 }
 
-struct CommitInfo {
-    hash: String,
-    author: String,
-    date: String,
-    message: String,
+/// Load the certificate chain and key as the web server would, to catch
+/// problems before it starts
+pub async fn check_tls(cert: PathBuf, key: PathBuf) -> Result<()> {
+    load_tls_config(&TlsFiles { cert, key }).await.map(|_| ())
+}
+
+async fn load_tls_config(tls: &TlsFiles) -> Result<RustlsConfig> {
+    // Several providers may be compiled in; pick ring explicitly
+    let _ = rustls::crypto::ring::default_provider().install_default();
+
+    for path in [&tls.cert, &tls.key] {
+        if !path.exists() {
+            anyhow::bail!("TLS file not found: {:?}", path);
+        }
+    }
+
+    RustlsConfig::from_pem_file(&tls.cert, &tls.key)
+        .await
+        .with_context(|| {
+            format!(
+                "Failed to load TLS certificate {:?} with key {:?}",
+                tls.cert, tls.key
+            )
+        })
+}
+
+/// Read the metadata shown on the index page for a single repository, or
+/// `None` if it is not a bare repository
+fn load_repository(name: String, repo_path: PathBuf) -> Option<Repository> {
+    if !git::is_bare_repo(&repo_path) {
+        tracing::warn!("Skipping non-bare repository {:?}", repo_path);
+        return None;
+    }
+
+    let mut repo = Repository {
+        name,
+        path: repo_path.clone(),
+        description: String::new(),
+        last_commit: String::new(),
+        last_commit_date: None,
+        branch_count: 0,
+        recent_commits: 0,
+        last_activity: None,
+        metadata: None,
+    };
+
+    // Get description
+    repo.description = git::repo_description(&repo_path).unwrap_or_default();
+
+    // Get last commit info
+    let output = git::git_command()
+        .arg("-C")
+        .arg(&repo_path)
+        .arg("log")
+        .arg("-1")
+        .arg(git::date_format("--format=%h - %s%x1f%cI"))
+        .output();
+
+    if let Ok(output) = output {
+        if output.status.success() {
+            let output = String::from_utf8_lossy(&output.stdout);
+            if let Some((summary, date)) = output.trim().rsplit_once('\x1f') {
+                repo.last_commit = summary.to_string();
+                repo.last_commit_date = git::parse_date(date);
+            }
+        }
+    }
+
+    repo.branch_count = git::branch_count(&repo_path);
+    repo.recent_commits = git::recent_commit_count(&repo_path, RECENT_DAYS);
+    repo.last_activity = git::last_activity(&repo_path);
+    repo.metadata = git::repo_metadata(&repo_path);
+
+    Some(repo)
 }
 
 struct FileInfo {
     name: String,
+    /// `blob`, `tree`, or `commit` for a submodule
     file_type: String,
+    /// Object id, which for a submodule is the commit it pins
+    object: String,
+}
+
+#[derive(Serialize)]
+struct RefInfo {
+    name: String,
+    #[serde(rename = "type")]
+    ref_type: &'static str,
+    target: String,
+}
+
+impl RefInfo {
+    /// Parse a `show-ref` line of the form `<sha> <refname>`
+    fn from_show_ref(line: &str) -> Option<Self> {
+        let (target, name) = line.split_once(' ')?;
+        let ref_type = if name.starts_with("refs/heads/") {
+            "branch"
+        } else if name.starts_with("refs/tags/") {
+            "tag"
+        } else {
+            "other"
+        };
+
+        Some(RefInfo {
+            name: name.to_string(),
+            ref_type,
+            target: target.to_string(),
+        })
+    }
+}
+
+#[derive(Deserialize)]
+struct IndexParams {
+    sort: Option<String>,
+    topic: Option<String>,
 }
 
-async fn handle_index(State(server): State<Arc<WebServer>>) -> Response {
-    match server.list_repositories() {
+async fn handle_index(
+    State(server): State<Arc<WebServer>>,
+    Query(params): Query<IndexParams>,
+    headers: HeaderMap,
+) -> Response {
+    match server.visible_repositories(&headers).await {
         Ok(repos) => {
+            let stats = index_stats(&repos);
+
+            let mut repos: Vec<&Repository> = repos.iter().collect();
+            if let Some(topic) = &params.topic {
+                repos.retain(|repo| {
+                    repo.metadata
+                        .as_ref()
+                        .is_some_and(|metadata| metadata.topics.contains(topic))
+                });
+            }
+            if params.sort.as_deref() == Some("activity") {
+                // Most recently active first; repos without a timestamp sort last
+                repos.sort_by_key(|repo| std::cmp::Reverse(repo.last_activity));
+            }
+
             let mut html = String::from(r#"<!DOCTYPE html>
 <html>
 <head>
@@ -259,23 +1051,58 @@ async fn handle_index(State(server): State<Arc<WebServer>>) -> Response {
         .repo-item a { text-decoration: none; }
         .repo-desc { color: #666; margin: 10px 0; }
         .repo-meta { color: #888; font-size: 0.9em; }
+        .repo-stats { color: #666; }
+        .topic { display: inline-block; background: #e1ecf4; color: #0066cc; padding: 2px 8px; margin-right: 4px; border-radius: 10px; font-size: 0.85em; text-decoration: none; }
     </style>
 </head>
 <body>
     <h1>Agito - Git Repositories</h1>
-    <div class="repo-list">
 "#);
+            html.push_str(&format!(r#"    <p class="repo-stats">{}</p>
+    <div class="repo-list">
+"#, stats));
+
+            if server.acl.is_some() {
+                html.push_str(r#"<p class="repo-meta"><a href="/login">Sign in</a> to see your repositories</p>"#);
+            }
+            if let Some(topic) = &params.topic {
+                html.push_str(&format!(
+                    r#"<p class="repo-meta">Topic: {} &middot; <a href="/">show all</a></p>"#,
+                    html_escape(topic)
+                ));
+            }
 
             for repo in repos {
+                let topics = repo
+                    .metadata
+                    .as_ref()
+                    .map(|metadata| topic_chips(&metadata.topics))
+                    .unwrap_or_default();
+                let committed = repo
+                    .last_commit_date
+                    .map(|date| format!(" ({})", time_tag(date)))
+                    .unwrap_or_default();
+                let branches = match repo.branch_count {
+                    0 => String::new(),
+                    1 => " &middot; 1 branch".to_string(),
+                    n => format!(" &middot; {} branches", n),
+                };
                 html.push_str(&format!(
                     r#"
         <div class="repo-item">
             <h2><a href="/repo/{}">{}</a></h2>
             <div class="repo-desc">{}</div>
-            <div class="repo-meta">{}</div>
+            <div class="topics">{}</div>
+            <div class="repo-meta">{}{}{}</div>
         </div>
 "#,
-                    repo.name, repo.name, repo.description, repo.last_commit
+                    html_escape(&repo_segment(&repo.name)),
+                    html_escape(&repo.name),
+                    html_escape(&repo.description),
+                    topics,
+                    html_escape(&repo.last_commit),
+                    committed,
+                    branches
                 ));
             }
 
@@ -287,7 +1114,7 @@ async fn handle_index(State(server): State<Arc<WebServer>>) -> Response {
 "#,
             );
 
-            Html(html).into_response()
+            server.revalidated_page(&headers, html)
         }
         Err(e) => (
             StatusCode::INTERNAL_SERVER_ERROR,
@@ -297,48 +1124,258 @@ async fn handle_index(State(server): State<Arc<WebServer>>) -> Response {
     }
 }
 
-async fn handle_repo(
-    State(server): State<Arc<WebServer>>,
-    Path(params): Path<String>,
-) -> Response {
-    let parts: Vec<&str> = params.split('/').collect();
-    let repo_name = parts[0];
-    let repo_path = server.repos_dir.join(repo_name);
+/// Summary line above the index, e.g. `12 repositories, 40 commits in the
+/// last 7 days`. Computed from the cached index, so it is as fresh as that.
+fn index_stats(repos: &[Repository]) -> String {
+    let commits: usize = repos.iter().map(|repo| repo.recent_commits).sum();
+    format!(
+        "{} {}, {} {} in the last {} days",
+        repos.len(),
+        if repos.len() == 1 { "repository" } else { "repositories" },
+        commits,
+        if commits == 1 { "commit" } else { "commits" },
+        RECENT_DAYS
+    )
+}
 
-    if !repo_path.exists() {
-        return (StatusCode::NOT_FOUND, "Repository not found").into_response();
+/// Have the browser ask for web auth credentials, then go back to the index,
+/// which now lists the repositories the web ACL gives the user
+async fn handle_login(State(server): State<Arc<WebServer>>, headers: HeaderMap) -> Response {
+    match server.require_user(&headers).await {
+        Ok(_) => Redirect::to("/").into_response(),
+        Err(rejection) => rejection.into_response(),
     }
+}
 
-    // Get branches
-    let branches = server.get_branches(&repo_path).unwrap_or_default();
-    let branch = branches.first().unwrap_or(&"master".to_string()).clone();
+async fn handle_api_repos(State(server): State<Arc<WebServer>>, headers: HeaderMap) -> Response {
+    match server.visible_repositories(&headers).await {
+        Ok(repos) => Json(repos.as_slice()).into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Error listing repositories: {}", e),
+        )
+            .into_response(),
+    }
+}
 
-    // Get description
-    let desc_path = repo_path.join("description");
-    let description = fs::read_to_string(&desc_path)
-        .unwrap_or_default()
-        .trim()
-        .to_string();
-    let description = if description
-        == "Unnamed repository; edit this file 'description' to name the repository."
-    {
-        String::new()
-    } else {
-        description
+async fn handle_sitemap(State(server): State<Arc<WebServer>>, headers: HeaderMap) -> Response {
+    let Some(origin) = &server.public_url else {
+        return (StatusCode::NOT_FOUND, "No sitemap without --public-url").into_response();
     };
+    match server.visible_repositories(&headers).await {
+        Ok(repos) => (
+            [(header::CONTENT_TYPE, "application/xml")],
+            sitemap_xml(origin, &repos),
+        )
+            .into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Error listing repositories: {}", e),
+        )
+            .into_response(),
+    }
+}
 
-    // Get commits
-    let commits = server.get_commits(&repo_path, 10).unwrap_or_default();
+async fn handle_robots(State(server): State<Arc<WebServer>>) -> Response {
+    (
+        [(header::CONTENT_TYPE, "text/plain; charset=utf-8")],
+        robots_txt(server.public_url.as_deref(), server.no_index),
+    )
+        .into_response()
+}
 
-    // Get files
-    let files = if parts.len() > 1 {
-        Vec::new()
-    } else {
-        server.list_files(&repo_path, &branch, "").unwrap_or_default()
-    };
+async fn handle_metrics(State(server): State<Arc<WebServer>>) -> Response {
+    let text = server.transfer_stats.as_ref().map(|stats| stats.render()).unwrap_or_default();
+    ([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], text).into_response()
+}
 
-    // Try to get README
-    let readme = server.get_readme(&repo_path, &branch).unwrap_or_default();
+async fn handle_favicon() -> Response {
+    (
+        [
+            (header::CONTENT_TYPE, "image/x-icon"),
+            (header::CACHE_CONTROL, "public, max-age=86400"),
+        ],
+        FAVICON,
+    )
+        .into_response()
+}
+
+/// Web app manifest, so the viewer can be pinned or installed with its icon
+async fn handle_manifest() -> Response {
+    (
+        [(header::CONTENT_TYPE, "application/manifest+json")],
+        Json(serde_json::json!({
+            "name": "Agito",
+            "short_name": "Agito",
+            "description": "Git repositories",
+            "start_url": "/",
+            "display": "browser",
+            "theme_color": "#0066cc",
+            "background_color": "#ffffff",
+            "icons": [{"src": "/favicon.ico", "sizes": "32x32", "type": "image/x-icon"}],
+        })),
+    )
+        .into_response()
+}
+
+/// Paths that crawlers are asked to skip. Each request runs git, and search
+/// engines gain nothing from them that the repository pages don't offer.
+const ROBOTS_DISALLOW: &[&str] = &[
+    "/api/",
+    "/repo/*/history/",
+    "/repo/*/search",
+    "/repo/*/raw/",
+    "/repo/*/commit/*.patch",
+    "/repo/*/refs",
+    "/repo/*/activity.json",
+    "/repo/*/events",
+    "/repo/*/info/lfs/",
+    "/metrics",
+];
+
+/// `robots.txt` for the site at `origin`, which names the sitemap when
+/// known, shutting out crawlers entirely when `no_index` is set
+fn robots_txt(origin: Option<&str>, no_index: bool) -> String {
+    if no_index {
+        return "User-agent: *\nDisallow: /\n".to_string();
+    }
+
+    let mut robots = String::from("User-agent: *\n");
+    for path in ROBOTS_DISALLOW {
+        robots.push_str(&format!("Disallow: {}\n", path));
+    }
+    if let Some(origin) = origin {
+        robots.push_str(&format!("\nSitemap: {}/sitemap.xml\n", origin));
+    }
+    robots
+}
+
+/// A sitemap of the index and every repository page under `origin`
+fn sitemap_xml(origin: &str, repos: &[Repository]) -> String {
+    let mut xml = String::from(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<urlset xmlns="http://www.sitemaps.org/schemas/sitemap/0.9">
+"#,
+    );
+
+    let last_activity = repos.iter().filter_map(|repo| repo.last_activity).max();
+    let pages = std::iter::once(("/".to_string(), last_activity)).chain(
+        repos
+            .iter()
+            .map(|repo| (format!("/repo/{}", repo_segment(&repo.name)), repo.last_activity)),
+    );
+    for (page, lastmod) in pages {
+        xml.push_str(&format!(
+            "  <url><loc>{}</loc>",
+            html_escape(&format!("{}{}", origin, page))
+        ));
+        if let Some(lastmod) = lastmod {
+            xml.push_str(&format!(
+                "<lastmod>{}</lastmod>",
+                lastmod.to_rfc3339_opts(chrono::SecondsFormat::Secs, true)
+            ));
+        }
+        xml.push_str("</url>\n");
+    }
+
+    xml.push_str("</urlset>\n");
+    xml
+}
+
+/// Details of a single repository returned by `/api/repos/:name`
+#[derive(Serialize)]
+struct RepoDetails {
+    name: String,
+    description: Option<String>,
+    default_branch: Option<String>,
+    last_activity: Option<DateTime<Utc>>,
+    objects: git::ObjectCounts,
+}
+
+async fn handle_api_repo(
+    State(server): State<Arc<WebServer>>,
+    Path(repo_name): Path<String>,
+    headers: HeaderMap,
+) -> Response {
+    let Some(repo_path) = server.visible_repo(&repo_name, &headers).await else {
+        return (StatusCode::NOT_FOUND, "Repository not found").into_response();
+    };
+
+    let details = tokio::task::spawn_blocking(move || {
+        git::count_objects(&repo_path).map(|objects| RepoDetails {
+            description: git::repo_description(&repo_path),
+            default_branch: git::default_branch(&repo_path),
+            last_activity: git::last_activity(&repo_path),
+            objects,
+            name: repo_name,
+        })
+    })
+    .await;
+
+    match details {
+        Ok(Ok(details)) => Json(details).into_response(),
+        Ok(Err(e)) => e.into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Error reading repository: {}", e),
+        )
+            .into_response(),
+    }
+}
+
+/// Default and maximum number of entries shown per page of a directory listing
+const TREE_PAGE_SIZE: usize = 200;
+const MAX_TREE_PAGE_SIZE: usize = 1000;
+
+#[derive(Deserialize)]
+struct TreeParams {
+    offset: Option<usize>,
+    limit: Option<usize>,
+}
+
+async fn handle_repo(
+    State(server): State<Arc<WebServer>>,
+    Path(params): Path<HashMap<String, String>>,
+    Query(page): Query<TreeParams>,
+    headers: HeaderMap,
+) -> Response {
+    let repo_name = params.get("name").map(String::as_str).unwrap_or_default();
+    let tree_path = params
+        .get("path")
+        .map(|path| path.trim_matches('/'))
+        .unwrap_or_default();
+    let Some(repo_path) = server.visible_repo(repo_name, &headers).await else {
+        return (StatusCode::NOT_FOUND, "Repository not found").into_response();
+    };
+
+    // Get branches
+    let branches = server.get_branches(&repo_path).unwrap_or_default();
+    let default_branch = git::default_branch(&repo_path);
+    let branch = default_branch
+        .clone()
+        .or_else(|| branches.first().cloned())
+        .unwrap_or_else(|| "master".to_string());
+
+    // Get description
+    let description = git::repo_description(&repo_path).unwrap_or_default();
+
+    // Get commits
+    let commits = server.get_commits(&repo_path, 10).unwrap_or_default();
+
+    // Get one page of files in the requested directory
+    let files = server
+        .list_files(&repo_path, &branch, tree_path)
+        .unwrap_or_default();
+    let total_files = files.len();
+    let offset = page.offset.unwrap_or(0).min(total_files);
+    let limit = page
+        .limit
+        .unwrap_or(TREE_PAGE_SIZE)
+        .clamp(1, MAX_TREE_PAGE_SIZE);
+    let files: Vec<FileInfo> = files.into_iter().skip(offset).take(limit).collect();
+
+    // Try to get the README of the directory being shown
+    let readme = server.get_readme(&repo_path, &branch, tree_path);
 
     let mut html = format!(
         r#"<!DOCTYPE html>
@@ -357,7 +1394,13 @@ async fn handle_repo(
         }}
         .file-item:hover, .commit-item:hover {{ background: #f5f5f5; }}
         .breadcrumb {{ color: #666; margin-bottom: 20px; }}
+        .repo-meta {{ color: #888; font-size: 0.9em; }}
+        .topic {{ display: inline-block; background: #e1ecf4; color: #0066cc; padding: 2px 8px; margin-right: 4px; border-radius: 10px; font-size: 0.85em; text-decoration: none; }}
+        {SIGNATURE_CSS}
         pre {{ background: #f5f5f5; padding: 15px; border-radius: 5px; overflow-x: auto; }}
+        .readme img {{ max-width: 100%; }}
+        .readme table {{ border-collapse: collapse; }}
+        .readme th, .readme td {{ border: 1px solid #ddd; padding: 4px 8px; }}
     </style>
 </head>
 <body>
@@ -367,41 +1410,1225 @@ async fn handle_repo(
     <h1>{}</h1>
     <p>{}</p>
 "#,
-        repo_name, repo_name, repo_name, description
+        html_escape(repo_name),
+        html_escape(repo_name),
+        html_escape(repo_name),
+        html_escape(&description)
     );
 
+    if let Some(default_branch) = &default_branch {
+        html.push_str(&format!(
+            r#"<p class="repo-meta">Default branch: {}</p>"#,
+            html_escape(default_branch)
+        ));
+    }
+
+    if git::is_archived(&repo_path) {
+        html.push_str(r#"<p class="repo-meta">Archived: read-only</p>"#);
+    }
+
+    if let Some(metadata) = git::repo_metadata(&repo_path) {
+        if let Some(created_at) = metadata.created_at {
+            let mut created = format!("Created on {}", created_at.format("%Y-%m-%d"));
+            if let Some(created_by) = metadata.created_by {
+                created.push_str(&format!(" by {}", html_escape(&created_by)));
+            }
+            html.push_str(&format!(r#"<p class="repo-meta">{}</p>"#, created));
+        }
+        if !metadata.topics.is_empty() {
+            html.push_str(&format!(
+                r#"<p class="topics">{}</p>"#,
+                topic_chips(&metadata.topics)
+            ));
+        }
+    }
+
+    // On disk, so people on metered connections know what a clone costs
+    if let Ok(objects) = git::count_objects(&repo_path) {
+        html.push_str(&format!(
+            r#"<p class="repo-meta">Repository size: {} ({} objects, {} {})</p>"#,
+            format_size((objects.size + objects.size_pack) * 1024),
+            objects.count + objects.in_pack,
+            objects.packs,
+            if objects.packs == 1 { "pack" } else { "packs" }
+        ));
+    }
+
     if !files.is_empty() {
-        html.push_str(r#"<div class="section"><h2>Files</h2><ul class="file-list">"#);
+        let shown = files.len();
+        let submodules = if files.iter().any(|file| file.file_type == "commit") {
+            git::submodule_urls(&repo_path, &branch)
+        } else {
+            HashMap::new()
+        };
+        let heading = if tree_path.is_empty() {
+            "Files".to_string()
+        } else {
+            format!("Files in {}", html_escape(tree_path))
+        };
+        html.push_str(&format!(
+            r#"<div class="section"><h2>{}</h2><ul class="file-list">"#,
+            heading
+        ));
         for file in files {
+            let name = html_escape(&file.name);
+            if file.file_type == "tree" {
+                let href = if tree_path.is_empty() {
+                    format!("/repo/{}/{}", repo_segment(repo_name), name)
+                } else {
+                    format!("/repo/{}/{}/{}", repo_segment(repo_name), html_escape(tree_path), name)
+                };
+                html.push_str(&format!(
+                    r#"<li class="file-item"><a href="{}">{}/</a> - {}</li>"#,
+                    href, name, file.file_type
+                ));
+            } else if file.file_type == "commit" {
+                let path = if tree_path.is_empty() {
+                    file.name.clone()
+                } else {
+                    format!("{}/{}", tree_path, file.name)
+                };
+                let short = &file.object[..8.min(file.object.len())];
+                let url = submodules.get(&path);
+                let entry = match url.and_then(|url| submodule_href(repo_name, url, &file.object)) {
+                    Some(href) => format!(r#"<a href="{}">{}</a>"#, html_escape(&href), name),
+                    None => name.clone(),
+                };
+                let source = url
+                    .map(|url| format!(" ({})", html_escape(url)))
+                    .unwrap_or_default();
+                html.push_str(&format!(
+                    r#"<li class="file-item submodule">{} @ <code>{}</code> - submodule{}</li>"#,
+                    entry, short, source
+                ));
+            } else {
+                let file_path = if tree_path.is_empty() {
+                    name.clone()
+                } else {
+                    format!("{}/{}", html_escape(tree_path), name)
+                };
+                html.push_str(&format!(
+                    r#"<li class="file-item">{} - {} (<a href="/repo/{}/history/{}/{}">history</a>)</li>"#,
+                    name,
+                    file.file_type,
+                    repo_segment(repo_name),
+                    html_escape(&branch),
+                    file_path
+                ));
+            }
+        }
+        html.push_str("</ul>");
+        if total_files > shown {
+            html.push_str(&format!(
+                r#"<p class="repo-meta">Showing {}-{} of {} entries"#,
+                offset + 1,
+                offset + shown,
+                total_files
+            ));
+            if offset + shown < total_files {
+                html.push_str(&format!(
+                    r#" &middot; <a href="?offset={}&amp;limit={}">Show more</a>"#,
+                    offset + shown,
+                    limit
+                ));
+            }
+            html.push_str("</p>");
+        }
+        html.push_str("</div>");
+    }
+
+    match readme {
+        Some((_, Ok(readme))) if !readme.is_empty() => {
+            html.push_str(&format!(
+                r#"<div class="section"><h2>README</h2><div class="readme">{}</div></div>"#,
+                readme
+            ));
+        }
+        Some((path, Err(e))) => {
+            html.push_str(&format!(
+                r#"<div class="section"><h2>README</h2><p>{}; <a href="/repo/{}/raw/{}/{}">download it instead</a>.</p></div>"#,
+                html_escape(&e.to_string()),
+                repo_segment(repo_name),
+                html_escape(&branch),
+                html_escape(&path)
+            ));
+        }
+        _ => {}
+    }
+
+    if !commits.is_empty() {
+        html.push_str(r#"<div class="section"><h2>Recent Commits</h2>"#);
+        html.push_str(&search_form(repo_name, "", git::CommitSearch::Message));
+        html.push_str(r#"<ul class="commit-list">"#);
+        for commit in commits {
             html.push_str(&format!(
-                r#"<li class="file-item">{} - {}</li>"#,
-                file.name, file.file_type
+                r#"<li class="commit-item"><strong><a href="/repo/{}/commit/{}">{}</a></strong>{} - {} <br/><small>{} by {}</small></li>"#,
+                repo_segment(repo_name), commit.hash, commit.hash, signature_badge(commit.signature), html_escape(&commit.message), time_tag(commit.date), html_escape(&commit.author)
             ));
         }
         html.push_str("</ul></div>");
     }
 
-    if !readme.is_empty() {
+    html.push_str("</body></html>");
+
+    server.revalidated_page(&headers, html)
+}
+
+/// Why a request to an editing endpoint was refused
+enum AuthRejection {
+    /// No web auth file is configured
+    Disabled,
+    /// Credentials are missing or wrong
+    Unauthenticated,
+}
+
+impl IntoResponse for AuthRejection {
+    fn into_response(self) -> Response {
+        match self {
+            AuthRejection::Disabled => {
+                (StatusCode::FORBIDDEN, "Web editing is not enabled").into_response()
+            }
+            AuthRejection::Unauthenticated => (
+                StatusCode::UNAUTHORIZED,
+                [(header::WWW_AUTHENTICATE, r#"Basic realm="agito""#)],
+                "Authentication required",
+            )
+                .into_response(),
+        }
+    }
+}
+
+impl IntoResponse for AgitoError {
+    fn into_response(self) -> Response {
+        let status = match self {
+            AgitoError::RepoNotFound(_) => StatusCode::NOT_FOUND,
+            AgitoError::InvalidName(_) => StatusCode::BAD_REQUEST,
+            AgitoError::AccessDenied(_) => StatusCode::FORBIDDEN,
+            AgitoError::QuotaExceeded(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            AgitoError::ContentMismatch(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            AgitoError::Git(_) | AgitoError::Io { .. } => {
+                tracing::error!("{}", self);
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        };
+        (status, self.to_string()).into_response()
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+struct DescriptionUpdate {
+    description: String,
+}
+
+/// Replace a repository's description from a JSON or form body
+async fn handle_set_description(
+    State(server): State<Arc<WebServer>>,
+    Path(repo_name): Path<String>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    if server.maintenance.is_enabled() {
+        let message = server.maintenance.message().to_string();
+        return (StatusCode::SERVICE_UNAVAILABLE, message).into_response();
+    }
+    let user = match server.require_user(&headers).await {
+        Ok(user) => user,
+        Err(rejection) => return rejection.into_response(),
+    };
+
+    let Some(repo_path) = server.repo_visible_to(&repo_name, Some(&user)) else {
+        return (StatusCode::NOT_FOUND, "Repository not found").into_response();
+    };
+    if !server.may_write(&repo_name, &user) {
+        return (StatusCode::FORBIDDEN, "No write access to this repository").into_response();
+    }
+    if git::is_archived(&repo_path) {
+        return (StatusCode::FORBIDDEN, "Repository is archived and read-only").into_response();
+    }
+
+    let is_json = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("application/json"));
+    let update: Option<DescriptionUpdate> = if is_json {
+        serde_json::from_slice(&body).ok()
+    } else {
+        serde_urlencoded::from_bytes(&body).ok()
+    };
+    let Some(update) = update else {
+        return (StatusCode::BAD_REQUEST, "Expected a description field").into_response();
+    };
+
+    match git::set_description(&repo_path, &update.description) {
+        Ok(description) => {
+            server.index_cache.invalidate();
+            tracing::info!("{} set description of {:?}", user, repo_path);
+            Json(DescriptionUpdate { description }).into_response()
+        }
+        Err(e) => e.into_response(),
+    }
+}
+
+async fn handle_refs(
+    State(server): State<Arc<WebServer>>,
+    Path(repo_name): Path<String>,
+    headers: HeaderMap,
+) -> Response {
+    let Some(repo_path) = server.visible_repo(&repo_name, &headers).await else {
+        return (StatusCode::NOT_FOUND, "Repository not found").into_response();
+    };
+
+    match git::list_refs(&repo_path) {
+        Ok(refs) => {
+            let refs: Vec<RefInfo> = refs
+                .iter()
+                .filter_map(|line| RefInfo::from_show_ref(line))
+                .collect();
+            Json(refs).into_response()
+        }
+        Err(e) => e.into_response(),
+    }
+}
+
+/// Most commits returned by the activity feed in one response
+const ACTIVITY_LIMIT: usize = 50;
+
+#[derive(Deserialize)]
+struct ActivityParams {
+    since: Option<String>,
+}
+
+/// Commits on the default branch after `?since=<sha>`, for CI systems that
+/// poll instead of cloning
+async fn handle_activity(
+    State(server): State<Arc<WebServer>>,
+    Path(repo_name): Path<String>,
+    Query(params): Query<ActivityParams>,
+    headers: HeaderMap,
+) -> Response {
+    let Some(repo_path) = server.visible_repo(&repo_name, &headers).await else {
+        return (StatusCode::NOT_FOUND, "Repository not found").into_response();
+    };
+
+    match git::activity(&repo_path, params.since.as_deref(), ACTIVITY_LIMIT) {
+        Ok(commits) => Json(commits).into_response(),
+        Err(e) => e.into_response(),
+    }
+}
+
+#[derive(Deserialize)]
+struct CommitParams {
+    /// `words` to highlight the changed words within modified lines
+    diff: Option<String>,
+}
+
+/// Serve `/repo/:name/commit/<hash>` as a commit page, or as `git format-patch`
+/// output when the hash ends in `.patch`
+async fn handle_commit(
+    State(server): State<Arc<WebServer>>,
+    Path((repo_name, hash)): Path<(String, String)>,
+    Query(params): Query<CommitParams>,
+    headers: HeaderMap,
+) -> Response {
+    let Some(repo_path) = server.visible_repo(&repo_name, &headers).await else {
+        return (StatusCode::NOT_FOUND, "Repository not found").into_response();
+    };
+
+    let (hash, patch) = match hash.strip_suffix(".patch") {
+        Some(hash) => (hash, true),
+        None => (hash.as_str(), false),
+    };
+    if !git::valid_commit_hash(hash) {
+        return (StatusCode::NOT_FOUND, "Commit not found").into_response();
+    }
+
+    if !patch {
+        let words = params.diff.as_deref() == Some("words");
+        return commit_page(&server, &repo_name, &repo_path, hash, words);
+    }
+
+    match git::format_patch(&repo_path, hash) {
+        Ok(Some(patch)) => (
+            [(header::CONTENT_TYPE, "text/plain; charset=utf-8")],
+            patch,
+        )
+            .into_response(),
+        Ok(None) => (StatusCode::NOT_FOUND, "Commit not found").into_response(),
+        Err(e) => e.into_response(),
+    }
+}
+
+fn commit_page(
+    server: &WebServer,
+    repo_name: &str,
+    repo_path: &std::path::Path,
+    hash: &str,
+    words: bool,
+) -> Response {
+    let (commit, stat, renames) = match git::show_commit(repo_path, hash) {
+        Ok(Some(details)) => details,
+        Ok(None) => return (StatusCode::NOT_FOUND, "Commit not found").into_response(),
+        Err(e) => return e.into_response(),
+    };
+
+    let mut html = format!(
+        r#"<!DOCTYPE html>
+<html>
+<head>
+    <title>Agito - {} {}</title>
+    <style>
+        body {{ font-family: Arial, sans-serif; margin: 40px; }}
+        h1 {{ color: #333; }}
+        .section {{ margin: 30px 0; }}
+        .section h2 {{ color: #0066cc; border-bottom: 2px solid #0066cc; padding-bottom: 5px; }}
+        .breadcrumb {{ color: #666; margin-bottom: 20px; }}
+        .repo-meta {{ color: #888; font-size: 0.9em; }}
+        pre {{ background: #f5f5f5; padding: 15px; border-radius: 5px; overflow-x: auto; }}
+        pre.message {{ white-space: pre-wrap; background: none; padding: 0; }}
+        .notes pre {{ background: #fff8e1; }}
+        {SIGNATURE_CSS}
+        .renames {{ list-style: none; padding: 0; font-family: monospace; }}
+        .similarity {{ color: #888; }}
+        pre.diff {{ padding: 0; }}
+        pre.diff span {{ display: block; padding: 0 15px; }}
+        pre.diff .diff-header {{ color: #666; font-weight: bold; }}
+        pre.diff .diff-hunk {{ color: #0066cc; background: #eef4fb; }}
+        pre.diff .diff-added {{ background: #e6f4ea; }}
+        pre.diff .diff-removed {{ background: #fdecea; }}
+        pre.diff .diff-words {{ background: #fff8e1; }}
+        pre.diff ins {{ background: #b7e4c7; text-decoration: none; }}
+        pre.diff del {{ background: #f5b7b1; text-decoration: none; }}
+        .diff-toggle {{ font-size: 0.6em; font-weight: normal; margin-left: 10px; }}
+    </style>
+</head>
+<body>
+    <div class="breadcrumb">
+        <a href="/">Home</a> / <a href="/repo/{}">{}</a> / {}
+    </div>
+    <h1>{}</h1>
+    <p class="repo-meta">{}{} by {}, {} &middot; <a href="/repo/{}/commit/{}.patch">Download patch</a></p>
+"#,
+        html_escape(repo_name),
+        hash,
+        html_escape(&repo_segment(repo_name)),
+        html_escape(repo_name),
+        hash,
+        linkify(&commit.message, server.issue_url.as_deref()),
+        commit.hash,
+        signature_badge(commit.signature),
+        html_escape(&commit.author),
+        time_tag(commit.date),
+        html_escape(&repo_segment(repo_name)),
+        hash
+    );
+
+    if !commit.body.is_empty() {
         html.push_str(&format!(
-            r#"<div class="section"><h2>README</h2><pre>{}</pre></div>"#,
-            html_escape(&readme)
+            r#"<pre class="message">{}</pre>"#,
+            linkify(&commit.body, server.issue_url.as_deref())
         ));
     }
 
-    if !commits.is_empty() {
-        html.push_str(r#"<div class="section"><h2>Recent Commits</h2><ul class="commit-list">"#);
-        for commit in commits {
+    if !renames.is_empty() {
+        html.push_str(r#"<div class="section"><h2>Renames</h2><ul class="renames">"#);
+        for rename in &renames {
             html.push_str(&format!(
-                r#"<li class="commit-item"><strong>{}</strong> - {} <br/><small>{} by {}</small></li>"#,
-                commit.hash, html_escape(&commit.message), commit.date, html_escape(&commit.author)
+                r#"<li>{} {} &rarr; {} <span class="similarity">({}% similar)</span></li>"#,
+                if rename.copy { "copied" } else { "renamed" },
+                html_escape(&rename.from),
+                html_escape(&rename.to),
+                rename.similarity
             ));
         }
         html.push_str("</ul></div>");
     }
 
+    if !stat.is_empty() {
+        html.push_str(&format!(
+            r#"<div class="section"><h2>Changes</h2><pre>{}</pre></div>"#,
+            html_escape(&stat)
+        ));
+    }
+
+    html.push_str(&diff_section(repo_name, hash, repo_path, words));
+
+    if let Some(notes) = git::commit_notes(repo_path, hash) {
+        html.push_str(&format!(
+            r#"<div class="section notes"><h2>Notes</h2><pre>{}</pre></div>"#,
+            html_escape(&notes)
+        ));
+    }
+
+    html.push_str("</body></html>");
+
+    server.themed(html).into_response()
+}
+
+/// The commit's patch, with a link to switch between line and word diffs
+fn diff_section(repo_name: &str, hash: &str, repo_path: &std::path::Path, words: bool) -> String {
+    let (toggle, label) = if words {
+        ("", "Line diff")
+    } else {
+        ("?diff=words", "Word diff")
+    };
+    let mut html = format!(
+        r#"<div class="section"><h2>Diff<a class="diff-toggle" href="/repo/{}/commit/{}{}">{}</a></h2>"#,
+        html_escape(&repo_segment(repo_name)),
+        hash,
+        toggle,
+        label
+    );
+
+    match git::commit_diff(repo_path, hash, words) {
+        Ok(Some(lines)) if !lines.is_empty() => {
+            html.push_str(r#"<pre class="diff">"#);
+            for line in &lines {
+                html.push_str(&render_diff_line(line));
+            }
+            html.push_str("</pre>");
+        }
+        Ok(Some(_)) => html.push_str("<p>No changes.</p>"),
+        Ok(None) | Err(_) => html.push_str(&format!(
+            r#"<p>This diff is too large to display. <a href="/repo/{}/commit/{}.patch">Download the patch</a> instead.</p>"#,
+            html_escape(&repo_segment(repo_name)),
+            hash
+        )),
+    }
+
+    html.push_str("</div>");
+    html
+}
+
+fn render_diff_line(line: &git::DiffLine) -> String {
+    let (class, prefix, text) = match line {
+        git::DiffLine::Header(text) => ("diff-header", "", html_escape(text)),
+        git::DiffLine::Hunk(text) => ("diff-hunk", "", html_escape(text)),
+        git::DiffLine::Context(text) => ("diff-context", " ", html_escape(text)),
+        git::DiffLine::Added(text) => ("diff-added", "+", html_escape(text)),
+        git::DiffLine::Removed(text) => ("diff-removed", "-", html_escape(text)),
+        git::DiffLine::Words(changes) => {
+            let text = changes
+                .iter()
+                .map(|change| match change {
+                    git::WordChange::Same(text) => html_escape(text),
+                    git::WordChange::Added(text) => format!("<ins>{}</ins>", html_escape(text)),
+                    git::WordChange::Removed(text) => format!("<del>{}</del>", html_escape(text)),
+                })
+                .collect();
+            ("diff-words", "~", text)
+        }
+    };
+    format!(r#"<span class="{}">{}{}</span>"#, class, prefix, text)
+}
+
+/// Stream a file's contents at a revision without buffering it in memory
+async fn handle_raw(
+    State(server): State<Arc<WebServer>>,
+    Path((repo_name, rev_path)): Path<(String, String)>,
+    headers: HeaderMap,
+) -> Response {
+    let Some(repo_path) = server.visible_repo(&repo_name, &headers).await else {
+        return (StatusCode::NOT_FOUND, "Repository not found").into_response();
+    };
+
+    // Branch names may contain `/`, so the rev can't be a route segment
+    let Some((id, size)) = git::split_rev_path(&repo_path, &rev_path)
+        .and_then(|(rev, file_path)| git::blob_at(&repo_path, &rev, &file_path))
+    else {
+        return (StatusCode::NOT_FOUND, "File not found").into_response();
+    };
+
+    let child = Command::from(git::git_command())
+        .arg("-C")
+        .arg(&repo_path)
+        .arg("cat-file")
+        .arg("blob")
+        .arg(&id)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .kill_on_drop(true)
+        .spawn();
+    let mut child = match child {
+        Ok(child) => child,
+        Err(e) => {
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Error reading file: {}", e),
+            )
+                .into_response()
+        }
+    };
+    let stdout = child.stdout.take().unwrap();
+
+    (
+        [
+            (header::CONTENT_TYPE, "application/octet-stream".to_string()),
+            (header::CONTENT_LENGTH, size.to_string()),
+        ],
+        Body::from_stream(read_stream(child, stdout)),
+    )
+        .into_response()
+}
+
+/// An LFS error, in the JSON form git-lfs shows to the user
+fn lfs_error(status: StatusCode, message: &str) -> Response {
+    (
+        status,
+        [(header::CONTENT_TYPE, lfs::LFS_MEDIA_TYPE)],
+        Json(serde_json::json!({ "message": message })),
+    )
+        .into_response()
+}
+
+/// Refuse an LFS upload, asking git-lfs for credentials when they're missing
+fn lfs_rejection(rejection: AuthRejection) -> Response {
+    match rejection {
+        AuthRejection::Disabled => {
+            lfs_error(StatusCode::FORBIDDEN, "LFS uploads need web auth to be enabled")
+        }
+        AuthRejection::Unauthenticated => {
+            let mut response = lfs_error(StatusCode::UNAUTHORIZED, "Authentication required");
+            let challenge = HeaderValue::from_static(r#"Basic realm="agito""#);
+            response
+                .headers_mut()
+                .insert(header::WWW_AUTHENTICATE, challenge.clone());
+            response.headers_mut().insert("LFS-Authenticate", challenge);
+            response
+        }
+    }
+}
+
+/// The Git LFS batch API: where to download or upload each requested object
+async fn handle_lfs_batch(
+    State(server): State<Arc<WebServer>>,
+    Path(repo_name): Path<String>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    let Some(store) = &server.lfs else {
+        return lfs_error(StatusCode::NOT_FOUND, "LFS is not enabled");
+    };
+    let Some(origin) = &server.public_url else {
+        return lfs_error(StatusCode::SERVICE_UNAVAILABLE, "The server has no public URL set");
+    };
+    let user = server.require_user(&headers).await;
+    let Some(repo_path) = server.repo_visible_to(&repo_name, user.as_deref().ok()) else {
+        return lfs_error(StatusCode::NOT_FOUND, "Repository not found");
+    };
+    let Ok(batch) = serde_json::from_slice::<lfs::BatchRequest>(&body) else {
+        return lfs_error(StatusCode::UNPROCESSABLE_ENTITY, "Malformed batch request");
+    };
+    if batch.hash_algo.as_deref().is_some_and(|algo| algo != lfs::HASH_ALGO) {
+        return lfs_error(StatusCode::CONFLICT, "Only sha256 objects are supported");
+    }
+    if !batch.transfers.is_empty() && !batch.transfers.iter().any(|t| t == lfs::BASIC_TRANSFER) {
+        return lfs_error(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "Only the basic transfer is supported",
+        );
+    }
+    if batch.operation == lfs::Operation::Upload {
+        let user = match user {
+            Ok(user) => user,
+            Err(rejection) => return lfs_rejection(rejection),
+        };
+        if !server.may_write(&repo_name, &user) {
+            return lfs_error(StatusCode::FORBIDDEN, "No write access to this repository");
+        }
+        if git::is_archived(&repo_path) {
+            return lfs_error(StatusCode::FORBIDDEN, "Repository is archived and read-only");
+        }
+    }
+
+    let objects_url = format!(
+        "{}/repo/{}/info/lfs/objects",
+        origin,
+        repo_segment(&repo_name)
+    );
+    let mut action_header = BTreeMap::new();
+    if let Some(auth) = headers.get(header::AUTHORIZATION).and_then(|v| v.to_str().ok()) {
+        action_header.insert("Authorization", auth.to_string());
+    }
+    let objects = batch
+        .objects
+        .into_iter()
+        .map(|object| {
+            store.batch_object(
+                &repo_path,
+                &repo_name,
+                batch.operation,
+                object,
+                &objects_url,
+                &action_header,
+            )
+        })
+        .collect();
+
+    (
+        [(header::CONTENT_TYPE, lfs::LFS_MEDIA_TYPE)],
+        Json(lfs::BatchResponse {
+            transfer: lfs::BASIC_TRANSFER,
+            objects,
+            hash_algo: lfs::HASH_ALGO,
+        }),
+    )
+        .into_response()
+}
+
+/// Download an LFS object, for the `download` actions of the batch API
+async fn handle_lfs_download(
+    State(server): State<Arc<WebServer>>,
+    Path((repo_name, oid)): Path<(String, String)>,
+    headers: HeaderMap,
+    request: axum::extract::Request,
+) -> Response {
+    let Some(store) = &server.lfs else {
+        return lfs_error(StatusCode::NOT_FOUND, "LFS is not enabled");
+    };
+    let Some(repo_path) = server.visible_repo(&repo_name, &headers).await else {
+        return lfs_error(StatusCode::NOT_FOUND, "Repository not found");
+    };
+    if !lfs::valid_oid(&oid) || store.object_size(&repo_path, &repo_name, &oid).is_none() {
+        return lfs_error(StatusCode::NOT_FOUND, "Object does not exist");
+    }
+
+    // ServeFile is always ready, and handles ranges for resumed downloads
+    let path = store.object_path(&repo_path, &repo_name, &oid);
+    match ServeFile::new(path).call(request).await {
+        Ok(response) => response.map(Body::new),
+        Err(e) => match e {},
+    }
+}
+
+#[derive(Deserialize)]
+struct LfsUploadQuery {
+    /// Size declared in the batch request, added to the upload action
+    size: u64,
+}
+
+/// Upload an LFS object, for the `upload` actions of the batch API
+async fn handle_lfs_upload(
+    State(server): State<Arc<WebServer>>,
+    Path((repo_name, oid)): Path<(String, String)>,
+    query: Option<Query<LfsUploadQuery>>,
+    headers: HeaderMap,
+    body: Body,
+) -> Response {
+    let Some(store) = &server.lfs else {
+        return lfs_error(StatusCode::NOT_FOUND, "LFS is not enabled");
+    };
+    let user = match server.require_user(&headers).await {
+        Ok(user) => user,
+        Err(rejection) => return lfs_rejection(rejection),
+    };
+    let Some(repo_path) = server.repo_visible_to(&repo_name, Some(&user)) else {
+        return lfs_error(StatusCode::NOT_FOUND, "Repository not found");
+    };
+    if !server.may_write(&repo_name, &user) {
+        return lfs_error(StatusCode::FORBIDDEN, "No write access to this repository");
+    }
+    if git::is_archived(&repo_path) {
+        return lfs_error(StatusCode::FORBIDDEN, "Repository is archived and read-only");
+    }
+    if !lfs::valid_oid(&oid) {
+        return lfs_error(StatusCode::UNPROCESSABLE_ENTITY, "Invalid object id");
+    }
+    let Some(Query(LfsUploadQuery { size })) = query else {
+        return lfs_error(StatusCode::UNPROCESSABLE_ENTITY, "Upload without a declared size");
+    };
+
+    // The raw body skips axum's body limit; write_object enforces its own
+    match store
+        .write_object(&repo_path, &repo_name, &oid, size, body.into_data_stream())
+        .await
+    {
+        Ok(()) => {
+            tracing::info!("{} uploaded LFS object {} ({} bytes) to {}", user, oid, size, repo_name);
+            StatusCode::OK.into_response()
+        }
+        Err(AgitoError::ContentMismatch(message)) => {
+            lfs_error(StatusCode::UNPROCESSABLE_ENTITY, &message)
+        }
+        Err(AgitoError::QuotaExceeded(message)) => {
+            lfs_error(StatusCode::PAYLOAD_TOO_LARGE, &message)
+        }
+        Err(e) => e.into_response(),
+    }
+}
+
+/// Stream a process's stdout, keeping the process alive until it is drained
+fn read_stream(
+    child: Child,
+    stdout: ChildStdout,
+) -> impl futures::Stream<Item = std::io::Result<Bytes>> {
+    stream::unfold(Some((child, stdout)), |state| async move {
+        let (child, mut stdout) = state?;
+        let mut buf = vec![0u8; git_io::DEFAULT_BUFFER_SIZE];
+        match stdout.read(&mut buf).await {
+            Ok(0) => None,
+            Ok(n) => {
+                buf.truncate(n);
+                Some((Ok(Bytes::from(buf)), Some((child, stdout))))
+            }
+            Err(e) => Some((Err(e), None)),
+        }
+    })
+}
+
+async fn handle_history(
+    State(server): State<Arc<WebServer>>,
+    Path((repo_name, rev, file_path)): Path<(String, String, String)>,
+    headers: HeaderMap,
+) -> Response {
+    let Some(repo_path) = server.visible_repo(&repo_name, &headers).await else {
+        return (StatusCode::NOT_FOUND, "Repository not found").into_response();
+    };
+
+    let file_path = file_path.trim_matches('/');
+    let commits = match git::file_history(&repo_path, &rev, file_path) {
+        Ok(commits) => commits,
+        Err(e) => return e.into_response(),
+    };
+
+    let mut html = format!(
+        r#"<!DOCTYPE html>
+<html>
+<head>
+    <title>Agito - History of {}</title>
+    <style>
+        body {{ font-family: Arial, sans-serif; margin: 40px; }}
+        h1 {{ color: #333; }}
+        .commit-list {{ list-style: none; padding: 0; }}
+        .commit-item {{ padding: 10px; border-bottom: 1px solid #eee; }}
+        .commit-item:hover {{ background: #f5f5f5; }}
+        .breadcrumb {{ color: #666; margin-bottom: 20px; }}
+        {SIGNATURE_CSS}
+    </style>
+</head>
+<body>
+    <div class="breadcrumb">
+        <a href="/">Home</a> / <a href="/repo/{}">{}</a> / {}
+    </div>
+    <h1>History of {}</h1>
+    <p>On {}</p>
+"#,
+        html_escape(file_path),
+        html_escape(&repo_segment(&repo_name)),
+        html_escape(&repo_name),
+        html_escape(file_path),
+        html_escape(file_path),
+        html_escape(&rev)
+    );
+
+    if commits.is_empty() {
+        html.push_str("<p>No commits touch this path.</p>");
+    } else {
+        html.push_str(r#"<ul class="commit-list">"#);
+        for commit in commits {
+            html.push_str(&format!(
+                r#"<li class="commit-item"><strong>{}</strong>{} - {} <br/><small>{} by {}</small></li>"#,
+                commit.hash, signature_badge(commit.signature), html_escape(&commit.message), time_tag(commit.date), html_escape(&commit.author)
+            ));
+        }
+        html.push_str("</ul>");
+    }
+
     html.push_str("</body></html>");
 
-    Html(html).into_response()
+    server.themed(html).into_response()
+}
+
+/// Stream `ref` server-sent events with `{"ref", "old", "new"}` data for
+/// every ref a push to the repository changes
+async fn handle_events(
+    State(server): State<Arc<WebServer>>,
+    Path(repo_name): Path<String>,
+    headers: HeaderMap,
+) -> Response {
+    let Some(repo_path) = server.visible_repo(&repo_name, &headers).await else {
+        return (StatusCode::NOT_FOUND, "Repository not found").into_response();
+    };
+    let repo = repo_path.canonicalize().unwrap_or(repo_path);
+
+    let receiver = server.ref_events.subscribe();
+    let events = stream::unfold((receiver, repo), |(mut receiver, repo)| async move {
+        loop {
+            match receiver.recv().await {
+                Ok(update) if update.repo == repo => {
+                    let event = Event::default().event("ref").json_data(&*update);
+                    return Some((event, (receiver, repo)));
+                }
+                Ok(_) => {}
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    tracing::warn!("Event subscriber fell behind; skipped {} update(s)", skipped);
+                }
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    });
+
+    Sse::new(events).keep_alive(KeepAlive::default()).into_response()
+}
+
+#[derive(Deserialize)]
+struct SearchParams {
+    q: Option<String>,
+    /// `message` (the default) or `author`
+    #[serde(rename = "type")]
+    field: Option<String>,
+}
+
+/// Serve `/repo/:name/search?q=<term>&type=message|author`, the commits on the
+/// default branch whose message or author contains the term
+async fn handle_search(
+    State(server): State<Arc<WebServer>>,
+    Path(repo_name): Path<String>,
+    Query(params): Query<SearchParams>,
+    headers: HeaderMap,
+) -> Response {
+    let Some(repo_path) = server.visible_repo(&repo_name, &headers).await else {
+        return (StatusCode::NOT_FOUND, "Repository not found").into_response();
+    };
+
+    let field = match params.field.as_deref() {
+        None | Some("message") => git::CommitSearch::Message,
+        Some("author") => git::CommitSearch::Author,
+        Some(other) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                format!("Unknown search type '{}'; use message or author", other),
+            )
+                .into_response()
+        }
+    };
+    let term = params.q.unwrap_or_default();
+    let term = term.trim();
+
+    let mut html = format!(
+        r#"<!DOCTYPE html>
+<html>
+<head>
+    <title>Agito - Search {}</title>
+    <style>
+        body {{ font-family: Arial, sans-serif; margin: 40px; }}
+        h1 {{ color: #333; }}
+        .commit-list {{ list-style: none; padding: 0; }}
+        .commit-item {{ padding: 10px; border-bottom: 1px solid #eee; }}
+        .commit-item:hover {{ background: #f5f5f5; }}
+        .breadcrumb {{ color: #666; margin-bottom: 20px; }}
+        {SIGNATURE_CSS}
+    </style>
+</head>
+<body>
+    <div class="breadcrumb">
+        <a href="/">Home</a> / <a href="/repo/{}">{}</a> / search
+    </div>
+    <h1>Search commits</h1>
+    {}
+"#,
+        html_escape(&repo_name),
+        html_escape(&repo_segment(&repo_name)),
+        html_escape(&repo_name),
+        search_form(&repo_name, term, field)
+    );
+
+    if !term.is_empty() {
+        let commits = match git::search_commits(&repo_path, field, term) {
+            Ok(commits) => commits,
+            Err(e) => return e.into_response(),
+        };
+
+        if commits.is_empty() {
+            html.push_str("<p>No matching commits.</p>");
+        } else {
+            if commits.len() == git::MAX_SEARCH_RESULTS {
+                html.push_str(&format!(
+                    "<p>Showing the {} most recent matches.</p>",
+                    git::MAX_SEARCH_RESULTS
+                ));
+            }
+            html.push_str(r#"<ul class="commit-list">"#);
+            for commit in commits {
+                html.push_str(&format!(
+                    r#"<li class="commit-item"><strong><a href="/repo/{}/commit/{}">{}</a></strong>{} - {} <br/><small>{} by {}</small></li>"#,
+                    repo_segment(&repo_name), commit.hash, commit.hash, signature_badge(commit.signature), html_escape(&commit.message), time_tag(commit.date), html_escape(&commit.author)
+                ));
+            }
+            html.push_str("</ul>");
+        }
+    }
+
+    html.push_str("</body></html>");
+
+    server.themed(html).into_response()
+}
+
+/// Form submitting to the commit search page of `repo_name`
+fn search_form(repo_name: &str, term: &str, field: git::CommitSearch) -> String {
+    let selected = |option| if field == option { " selected" } else { "" };
+    format!(
+        r#"<form class="commit-search" action="/repo/{}/search"><input type="search" name="q" value="{}" placeholder="Search commits"> <select name="type"><option value="message"{}>Message</option><option value="author"{}>Author</option></select> <button type="submit">Search</button></form>"#,
+        html_escape(&repo_segment(repo_name)),
+        html_escape(term),
+        selected(git::CommitSearch::Message),
+        selected(git::CommitSearch::Author)
+    )
+}
+
+/// Where a submodule entry links. Relative URLs name another repository on
+/// this server, so they link to the pinned commit there; http(s) URLs link to
+/// the submodule itself, since other hosts lay out commit pages differently.
+fn submodule_href(repo_name: &str, url: &str, commit: &str) -> Option<String> {
+    if url.starts_with("http://") || url.starts_with("https://") {
+        return Some(url.to_string());
+    }
+    if !url.starts_with("./") && !url.starts_with("../") {
+        return None;
+    }
+
+    // Resolved against the superproject's own path, as git does
+    let mut parts: Vec<&str> = repo_name.split('/').collect();
+    for part in url.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => {
+                parts.pop()?;
+            }
+            part => parts.push(part),
+        }
+    }
+    let mut name = parts.join("/");
+    if name.is_empty() {
+        return None;
+    }
+    if !name.ends_with(".git") {
+        name.push_str(".git");
+    }
+    Some(format!("/repo/{}/commit/{}", repo_segment(&name), commit))
+}
+
+/// Repository a request path is about, as logged by the access log
+fn access_log_repo(path: &str) -> Option<String> {
+    let rest = path
+        .strip_prefix("/repo/")
+        .or_else(|| path.strip_prefix("/api/repos/"))?;
+    let name = rest.split('/').next().filter(|name| !name.is_empty())?;
+    Some(name.replace("%2F", "/").replace("%2f", "/"))
+}
+
+/// A repository name as a single URL path segment. Nested names keep their
+/// `/` as `%2F`, which the router decodes back into `:name`.
+fn repo_segment(name: &str) -> String {
+    name.replace('/', "%2F")
+}
+
+/// Styles for [`signature_badge`], included by every page that lists commits
+const SIGNATURE_CSS: &str = "\
+.signature { display: inline-block; padding: 0 6px; margin-left: 4px; border-radius: 3px; font-size: 0.8em; }
+        .signature-good { background: #e6f4ea; color: #1e7e34; }
+        .signature-bad { background: #fdecea; color: #c62828; }
+        .signature-expired, .signature-unknown { background: #fff8e1; color: #8a6d00; }";
+
+/// Badge shown next to a commit hash for its signature; unsigned commits get
+/// none
+fn signature_badge(status: git::SignatureStatus) -> &'static str {
+    match status {
+        git::SignatureStatus::Good => {
+            r#" <span class="signature signature-good" title="Good signature">&#10003; Verified</span>"#
+        }
+        git::SignatureStatus::Bad => {
+            r#" <span class="signature signature-bad" title="The signature does not verify or its key was revoked">&#10007; Bad signature</span>"#
+        }
+        git::SignatureStatus::Expired => {
+            r#" <span class="signature signature-expired" title="The signature or its key has expired">&#9888; Expired</span>"#
+        }
+        git::SignatureStatus::Unknown => {
+            r#" <span class="signature signature-unknown" title="Signed with a key the server does not know">? Unverified</span>"#
+        }
+        git::SignatureStatus::Unsigned => "",
+    }
+}
+
+/// Strong entity tag for a response body
+fn etag(body: &[u8]) -> String {
+    format!("\"{}\"", HEXLOWER.encode(&Sha256::digest(body)))
+}
+
+/// Whether the request's `If-None-Match` lists `etag` or is `*`
+fn etag_matches(headers: &HeaderMap, etag: &str) -> bool {
+    headers
+        .get_all(header::IF_NONE_MATCH)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|tag| tag.trim())
+        .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+}
+
+/// `bytes` in the largest binary unit that keeps the number at least 1, with
+/// one decimal place above bytes, e.g. `12.3 MiB`
+fn format_size(bytes: u64) -> String {
+    const UNITS: &[&str] = &["KiB", "MiB", "GiB", "TiB"];
+
+    if bytes < 1024 {
+        return format!("{} bytes", bytes);
+    }
+
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+/// How long ago `date` was, in the largest whole unit, e.g. `3 days ago`
+fn format_relative(date: DateTime<Utc>) -> String {
+    relative_to(date, Utc::now())
+}
+
+fn relative_to(date: DateTime<Utc>, now: DateTime<Utc>) -> String {
+    let seconds = (now - date).num_seconds();
+    // Dates slightly in the future come from clock skew between machines
+    if seconds < 60 {
+        return "just now".to_string();
+    }
+
+    let (count, unit) = match seconds / 60 {
+        minutes if minutes < 60 => (minutes, "minute"),
+        minutes if minutes < 60 * 24 => (minutes / 60, "hour"),
+        minutes => match minutes / (60 * 24) {
+            days if days < 14 => (days, "day"),
+            days if days < 60 => (days / 7, "week"),
+            days if days < 365 => (days / 30, "month"),
+            days => (days / 365, "year"),
+        },
+    };
+    format!("{} {}{} ago", count, unit, if count == 1 { "" } else { "s" })
+}
+
+/// `date` as relative time, with the exact time as a tooltip
+fn time_tag(date: DateTime<Utc>) -> String {
+    format!(
+        r#"<time datetime="{}" title="{}">{}</time>"#,
+        date.to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+        date.format("%Y-%m-%d %H:%M:%S UTC"),
+        format_relative(date)
+    )
+}
+
+/// Render topics as links filtering the index by that topic
+fn topic_chips(topics: &[String]) -> String {
+    topics
+        .iter()
+        .map(|topic| {
+            let topic = html_escape(topic);
+            format!(r#"<a class="topic" href="/?topic={}">{}</a>"#, topic, topic)
+        })
+        .collect::<Vec<_>>()
+        .join("")
+}
+
+/// Escape `text` for HTML, then turn bare `http(s)://` URLs into links and,
+/// with an issue URL template, `#123` references into links to the tracker
+fn linkify(text: &str, issue_url: Option<&str>) -> String {
+    let escaped = html_escape(text);
+    let mut html = String::with_capacity(escaped.len());
+    let mut rest = escaped.as_str();
+    let mut at_boundary = true;
+
+    while let Some(c) = rest.chars().next() {
+        if at_boundary && (rest.starts_with("http://") || rest.starts_with("https://")) {
+            // Stop at whitespace or an escaped character other than `&`
+            let end = rest
+                .char_indices()
+                .find(|&(i, c)| {
+                    c.is_whitespace() || (c == '&' && !rest[i..].starts_with("&amp;"))
+                })
+                .map_or(rest.len(), |(i, _)| i);
+            // Leave sentence punctuation after a URL outside the link
+            let url = rest[..end].trim_end_matches(['.', ',', ';', ':', ')', '!', '?']);
+            html.push_str(&format!(r#"<a href="{}">{}</a>"#, url, url));
+            rest = &rest[url.len()..];
+            at_boundary = false;
+            continue;
+        }
+
+        if let (true, Some(template)) = (at_boundary && c == '#', issue_url) {
+            let digits = rest[1..]
+                .find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(rest.len() - 1);
+            let after = rest[1 + digits..].chars().next();
+            if digits > 0 && !after.is_some_and(|c| c.is_alphanumeric() || c == '_') {
+                let id = &rest[1..1 + digits];
+                let href = html_escape(&template.replace("{id}", id));
+                html.push_str(&format!(r##"<a href="{}">#{}</a>"##, href, id));
+                rest = &rest[1 + digits..];
+                at_boundary = false;
+                continue;
+            }
+        }
+
+        html.push(c);
+        rest = &rest[c.len_utf8()..];
+        at_boundary = c.is_whitespace() || matches!(c, '(' | '[' | ',');
+    }
+
+    html
+}
+
+/// Render README Markdown to HTML. Anyone with push access writes READMEs, so
+/// raw HTML in the source is shown as text and script URLs are dropped.
+fn render_markdown(source: &str) -> String {
+    use pulldown_cmark::{html, Event, Options, Parser, Tag};
+
+    let options =
+        Options::ENABLE_TABLES | Options::ENABLE_STRIKETHROUGH | Options::ENABLE_TASKLISTS;
+    let events = Parser::new_ext(source, options).map(|event| match event {
+        Event::Html(raw) | Event::InlineHtml(raw) => Event::Text(raw),
+        Event::Start(Tag::Link {
+            link_type,
+            dest_url,
+            title,
+            id,
+        }) => Event::Start(Tag::Link {
+            link_type,
+            dest_url: safe_url(dest_url),
+            title,
+            id,
+        }),
+        Event::Start(Tag::Image {
+            link_type,
+            dest_url,
+            title,
+            id,
+        }) => Event::Start(Tag::Image {
+            link_type,
+            dest_url: safe_url(dest_url),
+            title,
+            id,
+        }),
+        event => event,
+    });
+
+    let mut out = String::new();
+    html::push_html(&mut out, events);
+    out
+}
+
+/// `url`, or `#` if it would run script when followed
+fn safe_url(url: pulldown_cmark::CowStr) -> pulldown_cmark::CowStr {
+    let scheme = url.trim_start().split(':').next().unwrap_or_default();
+    let scheme: String = scheme.chars().filter(|c| !c.is_whitespace()).collect();
+    if ["javascript", "vbscript", "data"]
+        .iter()
+        .any(|bad| scheme.eq_ignore_ascii_case(bad))
+        && url.contains(':')
+    {
+        return "#".into();
+    }
+    url
 }
 
 fn html_escape(s: &str) -> String {
@@ -411,3 +2638,298 @@ fn html_escape(s: &str) -> String {
         .replace('"', "&quot;")
         .replace('\'', "&#39;")
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use std::fs;
+
+    #[test]
+    fn render_markdown_escapes_html_and_script_urls() {
+        let html = render_markdown("# Title\n\n*hi* <script>x</script> [a](javascript:alert(1)) [b](/docs)\n");
+        assert!(html.contains("<h1>Title</h1>"), "{}", html);
+        assert!(html.contains("<em>hi</em>"), "{}", html);
+        assert!(html.contains("&lt;script&gt;"), "{}", html);
+        assert!(html.contains(r##"<a href="#">a</a>"##), "{}", html);
+        assert!(html.contains(r#"<a href="/docs">b</a>"#), "{}", html);
+    }
+
+    #[test]
+    fn submodule_links() {
+        let commit = "0123456789abcdef0123456789abcdef01234567";
+        assert_eq!(
+            submodule_href("app.git", "../lib.git", commit),
+            Some(format!("/repo/lib.git/commit/{}", commit))
+        );
+        assert_eq!(
+            submodule_href("team/app.git", "../lib", commit),
+            Some(format!("/repo/team%2Flib.git/commit/{}", commit))
+        );
+        assert_eq!(
+            submodule_href("app.git", "https://example.com/lib.git", commit),
+            Some("https://example.com/lib.git".to_string())
+        );
+        assert_eq!(submodule_href("app.git", "git@example.com:lib.git", commit), None);
+        assert_eq!(submodule_href("app.git", "../../lib.git", commit), None);
+    }
+
+    #[test]
+    fn access_log_repo_from_path() {
+        assert_eq!(access_log_repo("/repo/demo.git"), Some("demo.git".to_string()));
+        assert_eq!(
+            access_log_repo("/repo/team%2Fapp.git/commit/abc123"),
+            Some("team/app.git".to_string())
+        );
+        assert_eq!(access_log_repo("/api/repos/demo.git"), Some("demo.git".to_string()));
+        assert_eq!(access_log_repo("/api/repos"), None);
+        assert_eq!(access_log_repo("/robots.txt"), None);
+    }
+
+    #[test]
+    fn ref_changes_between_snapshots() {
+        let snapshot = |refs: &[(&str, &str)]| -> BTreeMap<String, String> {
+            refs.iter()
+                .map(|(name, target)| (name.to_string(), target.to_string()))
+                .collect()
+        };
+        let before = snapshot(&[("refs/heads/main", "a1"), ("refs/heads/old", "b2"), ("refs/tags/v1", "c3")]);
+        let after = snapshot(&[("refs/heads/main", "d4"), ("refs/heads/new", "e5"), ("refs/tags/v1", "c3")]);
+        let null = crate::hooks::NULL_SHA.to_string();
+
+        let change = |name: &str, old: &str, new: &str| (name.to_string(), old.to_string(), new.to_string());
+        assert_eq!(
+            ref_changes(&before, &after),
+            vec![
+                change("refs/heads/main", "a1", "d4"),
+                change("refs/heads/new", &null, "e5"),
+                change("refs/heads/old", "b2", &null),
+            ]
+        );
+        assert!(ref_changes(&after, &after).is_empty());
+    }
+
+    #[test]
+    fn readme_cache_evicts_least_recently_used() {
+        let cache = ReadmeCache::new(2);
+        cache.insert("a".to_string(), "A".to_string());
+        cache.insert("b".to_string(), "B".to_string());
+        assert_eq!(cache.get("a").as_deref(), Some("A"));
+
+        cache.insert("c".to_string(), "C".to_string());
+        assert!(cache.get("b").is_none());
+        assert_eq!(cache.get("a").as_deref(), Some("A"));
+        assert_eq!(cache.get("c").as_deref(), Some("C"));
+    }
+
+    #[test]
+    fn linkify_urls_and_issue_references() {
+        assert_eq!(
+            linkify("See https://example.com/a?b=1&c=2.", None),
+            r#"See <a href="https://example.com/a?b=1&amp;c=2">https://example.com/a?b=1&amp;c=2</a>."#
+        );
+        assert_eq!(
+            linkify("Fixes #12 (and #3), not a#4 or #5x", Some("https://t.example/{id}")),
+            r##"Fixes <a href="https://t.example/12">#12</a> (and <a href="https://t.example/3">#3</a>), not a#4 or #5x"##
+        );
+        assert_eq!(linkify("Fixes #12", None), "Fixes #12");
+        assert_eq!(
+            linkify("<script>https://x.test</script>", None),
+            "&lt;script&gt;https://x.test&lt;/script&gt;"
+        );
+    }
+
+    #[test]
+    fn format_size_picks_readable_units() {
+        assert_eq!(format_size(0), "0 bytes");
+        assert_eq!(format_size(1023), "1023 bytes");
+        assert_eq!(format_size(1024), "1.0 KiB");
+        assert_eq!(format_size(12_900_000), "12.3 MiB");
+        assert_eq!(format_size(3 * 1024 * 1024 * 1024), "3.0 GiB");
+    }
+
+    #[test]
+    fn relative_time_uses_largest_whole_unit() {
+        let now = Utc.with_ymd_and_hms(2024, 3, 5, 12, 0, 0).unwrap();
+        let ago = |seconds| relative_to(now - chrono::Duration::seconds(seconds), now);
+
+        assert_eq!(ago(-30), "just now");
+        assert_eq!(ago(59), "just now");
+        assert_eq!(ago(60), "1 minute ago");
+        assert_eq!(ago(2 * 3600 + 59), "2 hours ago");
+        assert_eq!(ago(86400), "1 day ago");
+        assert_eq!(ago(20 * 86400), "2 weeks ago");
+        assert_eq!(ago(90 * 86400), "3 months ago");
+        assert_eq!(ago(800 * 86400), "2 years ago");
+
+        let tag = time_tag(now);
+        assert!(tag.starts_with(r#"<time datetime="2024-03-05T12:00:00Z" title="2024-03-05 12:00:00 UTC">"#));
+    }
+
+    #[test]
+    fn robots_txt_blocks_expensive_pages() {
+        let robots = robots_txt(Some("https://git.example"), false);
+        assert!(robots.starts_with("User-agent: *\n"));
+        assert!(robots.contains("Disallow: /repo/*/history/\n"));
+        assert!(robots.contains("Disallow: /repo/*/raw/\n"));
+        assert!(!robots.contains("Disallow: /\n"));
+        assert!(robots.ends_with("Sitemap: https://git.example/sitemap.xml\n"));
+
+        assert_eq!(robots_txt(None, true), "User-agent: *\nDisallow: /\n");
+        assert!(!robots_txt(None, false).contains("Sitemap"));
+    }
+
+    #[test]
+    fn sitemap_lists_index_and_repositories() {
+        let date = Utc.with_ymd_and_hms(2024, 3, 5, 7, 8, 9).unwrap();
+        let repo = |name: &str, last_activity| Repository {
+            name: name.to_string(),
+            path: PathBuf::new(),
+            description: String::new(),
+            last_commit: String::new(),
+            last_commit_date: None,
+            branch_count: 0,
+            recent_commits: 0,
+            last_activity,
+            metadata: None,
+        };
+
+        let xml = sitemap_xml(
+            "https://git.example",
+            &[repo("a&b.git", Some(date)), repo("empty.git", None)],
+        );
+        assert!(xml.contains(
+            "<url><loc>https://git.example/</loc><lastmod>2024-03-05T07:08:09Z</lastmod></url>"
+        ));
+        assert!(xml.contains(
+            "<url><loc>https://git.example/repo/a&amp;b.git</loc><lastmod>2024-03-05T07:08:09Z</lastmod></url>"
+        ));
+        assert!(xml.contains("<url><loc>https://git.example/repo/empty.git</loc></url>"));
+    }
+
+    #[test]
+    fn index_stats_sums_recent_commits() {
+        let repo = |recent_commits| Repository {
+            name: String::new(),
+            path: PathBuf::new(),
+            description: String::new(),
+            last_commit: String::new(),
+            last_commit_date: None,
+            branch_count: 0,
+            recent_commits,
+            last_activity: None,
+            metadata: None,
+        };
+
+        assert_eq!(index_stats(&[repo(1)]), "1 repository, 1 commit in the last 7 days");
+        assert_eq!(
+            index_stats(&[repo(3), repo(0), repo(4)]),
+            "3 repositories, 7 commits in the last 7 days"
+        );
+    }
+
+    #[test]
+    fn etag_matches_if_none_match_lists() {
+        let tag = etag(b"<html></html>");
+        let mut headers = HeaderMap::new();
+        assert!(!etag_matches(&headers, &tag));
+
+        headers.insert(header::IF_NONE_MATCH, format!("\"old\", W/{}", tag).parse().unwrap());
+        assert!(etag_matches(&headers, &tag));
+        assert!(!etag_matches(&headers, &etag(b"<html>changed</html>")));
+
+        headers.insert(header::IF_NONE_MATCH, "*".parse().unwrap());
+        assert!(etag_matches(&headers, &tag));
+    }
+
+    #[tokio::test]
+    async fn index_is_revalidated_per_visitor() {
+        let root = std::env::temp_dir().join(format!("agito-web-etag-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        let repos = root.join("repos");
+        for name in ["docs.git", "team-a/app.git"] {
+            assert!(git::create_bare_repo(&repos.join(name), None).unwrap());
+        }
+        let auth_file = root.join("users");
+        let acl_file = root.join("acl");
+        fs::write(&auth_file, format!("alice:{}\n", web_auth::hash_password("secret"))).unwrap();
+        fs::write(&acl_file, "*: docs\nalice: team-a/*\n").unwrap();
+
+        let router = WebServer::new(repos, IndexCache::new(Duration::ZERO))
+            .auth(WebAuth::new(auth_file))
+            .acl(WebAcl::new(acl_file))
+            .router();
+        let index = |etag: Option<&HeaderValue>, signed_in: bool| {
+            let mut request = axum::http::Request::get("/");
+            if let Some(etag) = etag {
+                request = request.header(header::IF_NONE_MATCH, etag);
+            }
+            if signed_in {
+                // "alice:secret"
+                request = request.header(header::AUTHORIZATION, "Basic YWxpY2U6c2VjcmV0");
+            }
+            request.body(Body::empty()).unwrap()
+        };
+
+        let response = router.clone().call(index(None, false)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CACHE_CONTROL], "private, no-cache");
+        let etag = response.headers()[header::ETAG].clone();
+
+        let response = router.clone().call(index(Some(&etag), false)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(response.headers()[header::ETAG], etag);
+
+        // Signing in shows more repositories, so the anonymous copy is stale
+        let response = router.clone().call(index(Some(&etag), true)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_ne!(response.headers()[header::ETAG], etag);
+
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    #[tokio::test]
+    async fn description_edits_need_the_users_own_acl_line() {
+        // Router is always ready, so requests can be sent without poll_ready
+        use tower::Service;
+
+        let root = std::env::temp_dir().join(format!("agito-web-edit-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        let repos = root.join("repos");
+        for name in ["docs.git", "team-a/app.git"] {
+            assert!(git::create_bare_repo(&repos.join(name), None).unwrap());
+        }
+        let auth_file = root.join("users");
+        let acl_file = root.join("acl");
+        fs::write(&auth_file, format!("alice:{}\n", web_auth::hash_password("secret"))).unwrap();
+        fs::write(&acl_file, "*: docs\nalice: team-a/*\n").unwrap();
+
+        let router = WebServer::new(repos, IndexCache::new(Duration::ZERO))
+            .auth(WebAuth::new(auth_file))
+            .acl(WebAcl::new(acl_file))
+            .router();
+        let edit = |repo: &str| {
+            axum::http::Request::patch(format!("/repo/{}/description", repo))
+                // "alice:secret"
+                .header(header::AUTHORIZATION, "Basic YWxpY2U6c2VjcmV0")
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(r#"{"description":"Edited"}"#))
+                .unwrap()
+        };
+
+        // Visible to alice only through the `*` line
+        let response = router.clone().call(edit("docs.git")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let response = router.clone().call(edit("team-a%2Fapp.git")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let router = WebServer::new(root.join("repos"), IndexCache::new(Duration::ZERO))
+            .auth(WebAuth::new(root.join("users")))
+            .maintenance(Maintenance::new(true, None, "Back soon".to_string()))
+            .router();
+        let response = router.clone().call(edit("team-a%2Fapp.git")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        let _ = fs::remove_dir_all(&root);
+    }
+}
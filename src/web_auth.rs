@@ -0,0 +1,290 @@
+use crate::hooks;
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use data_encoding::BASE64;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+/// How long verified credentials are trusted without hashing the password
+/// again, as long as the user's entry in the file is unchanged
+const VERIFIED_TTL: Duration = Duration::from_secs(60);
+
+/// HTTP Basic credentials for the web viewer's editing endpoints.
+///
+/// The file holds one `user:<argon2 hash>` entry per line, as printed by
+/// [`hash_password`], with `#` comments. It is re-read on every check like
+/// `authorized_keys` is for SSH, so edits apply without a restart.
+///
+/// Argon2 is deliberately slow, so credentials that matched are remembered
+/// for [`VERIFIED_TTL`] by a digest of the header, together with the entry
+/// they matched; changing or removing the entry takes effect at once.
+#[derive(Clone)]
+pub struct WebAuth {
+    path: PathBuf,
+    verified: Arc<Mutex<HashMap<Vec<u8>, Verified>>>,
+}
+
+/// Credentials that recently matched an entry of the file
+struct Verified {
+    user: String,
+    entry: String,
+    at: Instant,
+}
+
+impl WebAuth {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            verified: Arc::default(),
+        }
+    }
+
+    /// The user named by an `Authorization` header, if its credentials match.
+    ///
+    /// This can take a while; async callers should run it on a blocking
+    /// thread.
+    pub fn authenticate(&self, authorization: &str) -> Option<String> {
+        let (user, password) = parse_basic(authorization)?;
+
+        let entries = match fs::read_to_string(&self.path) {
+            Ok(entries) => entries,
+            Err(e) => {
+                tracing::warn!("Failed to read web auth file {:?}: {}", self.path, e);
+                return None;
+            }
+        };
+
+        let stored = entries
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .filter_map(|line| line.split_once(':'))
+            .find(|(name, _)| *name == user)
+            .map(|(_, stored)| stored);
+        let Some(stored) = stored else {
+            // Unknown users take as long as wrong passwords
+            let _ = verify(&password, &dummy_hash());
+            return None;
+        };
+
+        let key = Sha256::digest(authorization.as_bytes()).to_vec();
+        if self.recently_verified(&key, &user, stored) {
+            return Some(user);
+        }
+
+        let Ok(hash) = PasswordHash::new(stored) else {
+            tracing::warn!(
+                "Web auth entry for {} is not an argon2 hash; replace it with the output of \
+                 agito-server --hash-password",
+                user
+            );
+            return None;
+        };
+        if !verify(&password, &hash) {
+            return None;
+        }
+
+        let mut verified = self.verified.lock().unwrap();
+        verified.retain(|_, verified| verified.at.elapsed() < VERIFIED_TTL);
+        let entry = stored.to_string();
+        verified.insert(key, Verified { user: user.clone(), entry, at: Instant::now() });
+        Some(user)
+    }
+
+    /// Whether the credentials with digest `key` matched `user`'s current
+    /// `entry` within [`VERIFIED_TTL`]
+    fn recently_verified(&self, key: &[u8], user: &str, entry: &str) -> bool {
+        let verified = self.verified.lock().unwrap();
+        verified.get(key).is_some_and(|verified| {
+            verified.user == user && verified.entry == entry && verified.at.elapsed() < VERIFIED_TTL
+        })
+    }
+}
+
+/// Hash a password for the web auth file, with a random salt
+pub fn hash_password(password: &str) -> String {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .expect("argon2 hashing with default parameters cannot fail")
+        .to_string()
+}
+
+/// Whether `password` matches `hash`, compared in constant time
+fn verify(password: &str, hash: &PasswordHash) -> bool {
+    Argon2::default()
+        .verify_password(password.as_bytes(), hash)
+        .is_ok()
+}
+
+/// Hash checked against for unknown users
+fn dummy_hash() -> PasswordHash<'static> {
+    static DUMMY: OnceLock<String> = OnceLock::new();
+    let hash = DUMMY.get_or_init(|| hash_password(""));
+    PasswordHash::new(hash).expect("hash_password output parses")
+}
+
+/// Which repositories each web user may see.
+///
+/// The file holds one `user: pattern...` line per user, where patterns are
+/// repository names with `*` wildcards, and `#` starts a comment. Patterns
+/// listed for the `*` user apply to everyone, including visitors without
+/// credentials; a user given the pattern `*` sees every repository. Re-read
+/// on every check like [`WebAuth`].
+#[derive(Clone)]
+pub struct WebAcl {
+    path: PathBuf,
+}
+
+impl WebAcl {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+
+    /// Repository patterns visible to `user`, or to visitors without
+    /// credentials when `None`. An unreadable file allows nothing.
+    pub fn patterns(&self, user: Option<&str>) -> Vec<String> {
+        self.read_patterns(|name| name == "*" || Some(name) == user)
+    }
+
+    /// Repository patterns listed for `user` by name, leaving out the `*`
+    /// patterns everyone gets. These are the repositories `user` may change.
+    pub fn own_patterns(&self, user: &str) -> Vec<String> {
+        self.read_patterns(|name| name == user)
+    }
+
+    /// Whether `user`'s own line has the pattern `*`, which makes them an
+    /// administrator who also sees hidden repositories
+    pub fn is_admin(&self, user: &str) -> bool {
+        self.own_patterns(user).iter().any(|pattern| pattern == "*")
+    }
+
+    fn read_patterns(&self, listed: impl Fn(&str) -> bool) -> Vec<String> {
+        let entries = match fs::read_to_string(&self.path) {
+            Ok(entries) => entries,
+            Err(e) => {
+                tracing::warn!("Failed to read web ACL file {:?}: {}", self.path, e);
+                return Vec::new();
+            }
+        };
+
+        entries
+            .lines()
+            .map(|line| line.split('#').next().unwrap_or_default().trim())
+            .filter_map(|line| line.split_once(':'))
+            .filter(|(name, _)| listed(name.trim()))
+            .flat_map(|(_, patterns)| patterns.split_whitespace())
+            .map(|pattern| pattern.to_string())
+            .collect()
+    }
+}
+
+/// Whether repository `name` matches one of `patterns`, with or without its
+/// `.git` suffix
+pub fn repo_allowed(patterns: &[String], name: &str) -> bool {
+    let short = name.strip_suffix(".git").unwrap_or(name);
+    patterns
+        .iter()
+        .any(|pattern| hooks::branch_matches(pattern, name) || hooks::branch_matches(pattern, short))
+}
+
+/// Split a `Basic <base64(user:password)>` header value into its parts
+fn parse_basic(authorization: &str) -> Option<(String, String)> {
+    let encoded = authorization.strip_prefix("Basic ")?.trim();
+    let decoded = BASE64.decode(encoded.as_bytes()).ok()?;
+    let decoded = String::from_utf8(decoded).ok()?;
+    let (user, password) = decoded.split_once(':')?;
+    Some((user.to_string(), password.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_basic_decodes_credentials() {
+        // "alice:open:sesame"
+        assert_eq!(
+            parse_basic("Basic YWxpY2U6b3BlbjpzZXNhbWU="),
+            Some(("alice".to_string(), "open:sesame".to_string()))
+        );
+        assert_eq!(parse_basic("Bearer token"), None);
+        assert_eq!(parse_basic("Basic !!!"), None);
+    }
+
+    #[test]
+    fn authenticate_checks_password_hash() {
+        let path = std::env::temp_dir().join(format!("agito-web-auth-{}", std::process::id()));
+        let hash = hash_password("secret");
+        assert_ne!(hash, hash_password("secret"), "hashes are salted");
+        fs::write(
+            &path,
+            format!(
+                // sha256("secret"), no longer accepted
+                "# users\nalice:{}\nbob:2bb80d537b1da3e38bd30361aa855686bde0eacd7162fef6a25fe97bf527a25b\n",
+                hash
+            ),
+        )
+        .unwrap();
+        let auth = WebAuth::new(path.clone());
+
+        // "alice:secret", "alice:wrong", "bob:secret" and "carol:secret"
+        assert_eq!(auth.authenticate("Basic YWxpY2U6c2VjcmV0"), Some("alice".to_string()));
+        assert_eq!(auth.authenticate("Basic YWxpY2U6d3Jvbmc="), None);
+        assert_eq!(auth.authenticate("Basic Ym9iOnNlY3JldA=="), None);
+        assert_eq!(auth.authenticate("Basic Y2Fyb2w6c2VjcmV0"), None);
+
+        // Remembered credentials still follow changes to the file
+        assert_eq!(auth.authenticate("Basic YWxpY2U6c2VjcmV0"), Some("alice".to_string()));
+        fs::write(&path, format!("alice:{}\n", hash_password("changed"))).unwrap();
+        assert_eq!(auth.authenticate("Basic YWxpY2U6c2VjcmV0"), None);
+        fs::write(&path, "").unwrap();
+        assert_eq!(auth.authenticate("Basic YWxpY2U6c2VjcmV0"), None);
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn acl_patterns_per_user() {
+        let path = std::env::temp_dir().join(format!("agito-web-acl-{}", std::process::id()));
+        fs::write(
+            &path,
+            "# everyone
+*: docs
+alice: team-a/* shared.git
+admin: *  # all of them
+",
+        )
+        .unwrap();
+        let acl = WebAcl::new(path.clone());
+
+        let anonymous = acl.patterns(None);
+        assert!(repo_allowed(&anonymous, "docs.git"));
+        assert!(!repo_allowed(&anonymous, "shared.git"));
+
+        let alice = acl.patterns(Some("alice"));
+        assert!(repo_allowed(&alice, "docs.git"));
+        assert!(repo_allowed(&alice, "team-a/app.git"));
+        assert!(repo_allowed(&alice, "shared.git"));
+        assert!(!repo_allowed(&alice, "team-b/app.git"));
+
+        assert!(repo_allowed(&acl.patterns(Some("admin")), "team-b/app.git"));
+        assert!(!repo_allowed(&acl.patterns(Some("mallory")), "shared.git"));
+
+        // Only a user's own line grants changes, not the `*` patterns
+        assert!(repo_allowed(&acl.own_patterns("alice"), "team-a/app.git"));
+        assert!(!repo_allowed(&acl.own_patterns("alice"), "docs.git"));
+        assert!(acl.own_patterns("mallory").is_empty());
+
+        assert!(acl.is_admin("admin"));
+        assert!(!acl.is_admin("alice"));
+        assert!(!acl.is_admin("mallory"));
+
+        let _ = fs::remove_file(&path);
+        assert!(acl.patterns(Some("admin")).is_empty());
+    }
+}